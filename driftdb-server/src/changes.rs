@@ -0,0 +1,122 @@
+//! A feed of every change made to a room, as newline-delimited JSON, for
+//! external consumers such as analytics and backup jobs.
+
+use crate::{
+    config::Config,
+    room::{ConnectionGuard, RoomError, Rooms},
+    server::check_api_key,
+    shutdown::ShutdownSignal,
+};
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+};
+use driftdb::{types::SequenceNumber, ApplyResult, Store};
+use hyper::{
+    header::{self, HeaderMap},
+    StatusCode,
+};
+use serde::Deserialize;
+use std::{convert::Infallible, sync::Arc};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    /// Send changes after this sequence number. If omitted, only changes
+    /// made after the request are sent.
+    seq: Option<u64>,
+}
+
+fn encode(result: &ApplyResult) -> Bytes {
+    let mut line = serde_json::to_vec(result).expect("Changes should serialize as JSON.");
+    line.push(b'\n');
+    line.into()
+}
+
+async fn stream_changes(
+    _guard: ConnectionGuard,
+    backlog: Vec<ApplyResult>,
+    mut changes: broadcast::Receiver<ApplyResult>,
+    sender: mpsc::Sender<Result<Bytes, Infallible>>,
+    mut shutdown: ShutdownSignal,
+    mut closed: ShutdownSignal,
+) {
+    for result in &backlog {
+        if sender.send(Ok(encode(result))).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            result = changes.recv() => {
+                let result = match result {
+                    Ok(result) => result,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // End the feed; the consumer can resume from the last
+                        // change it received.
+                        tracing::warn!(?skipped, "Change feed fell behind.");
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if sender.send(Ok(encode(&result))).await.is_err() {
+                    break;
+                }
+            }
+            _ = sender.closed() => {
+                // The consumer has gone away.
+                break;
+            }
+            _ = shutdown.wait() => break,
+            _ = closed.wait() => break,
+        }
+    }
+}
+
+/// Stream every change made to a room, one JSON-encoded [ApplyResult] per line.
+pub async fn changes(
+    Path(room_id): Path<String>,
+    Query(query): Query<ChangesQuery>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    State(shutdown): State<ShutdownSignal>,
+    headers: HeaderMap,
+) -> Result<Response, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    let (room, guard) = rooms.connect(&room_id)?;
+
+    let seq = match query.seq {
+        Some(seq) => SequenceNumber(seq),
+        None => room.database.with_store(Store::sequence_number),
+    };
+    let Some((backlog, changes)) = room.changes_since(seq) else {
+        return Ok((
+            StatusCode::GONE,
+            format!("Changes after sequence number {} are not available.", seq.0),
+        )
+            .into_response());
+    };
+
+    let (sender, receiver) = mpsc::channel(32);
+    tokio::spawn(stream_changes(
+        guard,
+        backlog,
+        changes,
+        sender,
+        shutdown,
+        room.closed(),
+    ));
+
+    Ok((
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        StreamBody::new(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
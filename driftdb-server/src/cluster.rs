@@ -0,0 +1,601 @@
+//! Running several servers as a cluster. Nodes share a storage directory,
+//! in which each announces itself with a heartbeat file. Every room is owned
+//! by one live node, chosen by rendezvous hashing; other nodes redirect HTTP
+//! requests for the room to its owner and proxy WebSocket connections to it.
+//! A node holds a lease file for each room it has loaded, so that a new owner
+//! waits for the old one to save the room and release it.
+
+use crate::{config::ClusterConfig, room::Rooms, shutdown::ShutdownSignal};
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket},
+        FromRequestParts, State, WebSocketUpgrade,
+    },
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use futures_util::{SinkExt, StreamExt};
+use hyper::{header, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, protocol::frame::coding::CloseCode},
+    MaybeTlsStream, WebSocketStream,
+};
+
+/// Header added to requests proxied from another node, holding the cluster
+/// secret. The receiving node serves these itself even if it disagrees about
+/// who owns the room, so that nodes with briefly different views of the
+/// cluster cannot loop.
+const PROXIED_HEADER: &str = "x-driftdb-proxied";
+
+/// Directory, within the storage directory, holding heartbeat files.
+const NODES_DIR: &str = "nodes";
+
+/// Directory, within the storage directory, holding room leases. Each lease
+/// file is named after a room and holds the ID of the node serving it.
+const LEASES_DIR: &str = "leases";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Heartbeat {
+    #[serde(flatten)]
+    node: Node,
+
+    /// Unix timestamp (in seconds) at which the heartbeat was written.
+    timestamp: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Choose the owner of a room among `members` by rendezvous hashing: each
+/// node scores the room, and the highest score wins. When a node joins or
+/// leaves, only the rooms it gains or loses change owner.
+fn owner_of<'a>(members: &'a [Node], room_id: &str) -> Option<&'a Node> {
+    members.iter().max_by_key(|node| {
+        let digest = Sha256::new()
+            .chain_update(node.id.as_bytes())
+            .chain_update([0])
+            .chain_update(room_id.as_bytes())
+            .finalize();
+        let score = u64::from_be_bytes(digest[..8].try_into().unwrap());
+
+        (score, &node.id)
+    })
+}
+
+pub struct Cluster {
+    local: Node,
+    nodes_dir: PathBuf,
+    leases_dir: PathBuf,
+    heartbeat_interval: Duration,
+    node_timeout: u64,
+    secret: String,
+
+    /// Live nodes, including this one, sorted by ID.
+    members: RwLock<Vec<Node>>,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig, storage_path: &Path) -> Result<Self> {
+        let nodes_dir = storage_path.join(NODES_DIR);
+        let leases_dir = storage_path.join(LEASES_DIR);
+        for dir in [&nodes_dir, &leases_dir] {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+
+        let local = Node {
+            id: config.node_id.clone(),
+            url: config.advertise_url.trim_end_matches('/').to_string(),
+        };
+
+        let cluster = Self {
+            members: RwLock::new(vec![local.clone()]),
+            local,
+            nodes_dir,
+            leases_dir,
+            heartbeat_interval: Duration::from_secs(config.heartbeat_seconds),
+            node_timeout: config.node_timeout_seconds,
+            secret: config.secret.clone(),
+        };
+        cluster.release_stale_leases()?;
+
+        Ok(cluster)
+    }
+
+    /// Whether a request was proxied from another node of the cluster, as
+    /// opposed to claiming to be by a client which doesn't know the secret.
+    fn is_proxied(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(PROXIED_HEADER)
+            .map(|value| bool::from(value.as_bytes().ct_eq(self.secret.as_bytes())))
+            .unwrap_or(false)
+    }
+
+    /// The node which owns the given room.
+    pub fn owner(&self, room_id: &str) -> Node {
+        let members = self.members.read().unwrap();
+        owner_of(&members, room_id)
+            .cloned()
+            .unwrap_or_else(|| self.local.clone())
+    }
+
+    /// The node which owns the given room, if it is not this one.
+    pub fn remote_owner(&self, room_id: &str) -> Option<Node> {
+        let owner = self.owner(room_id);
+
+        (owner != self.local).then_some(owner)
+    }
+
+    pub fn is_local(&self, room_id: &str) -> bool {
+        self.remote_owner(room_id).is_none()
+    }
+
+    fn lease_path(&self, room_id: &str) -> PathBuf {
+        // Room IDs are validated to be safe to use as file names.
+        self.leases_dir.join(room_id)
+    }
+
+    /// Take the lease on a room, which a node must hold to load or create
+    /// it. Returns false if another live node holds the lease, e.g. because
+    /// it has not yet saved the room and handed it off. Leases held by nodes
+    /// which are gone are taken over.
+    pub fn acquire_lease(&self, room_id: &str) -> Result<bool> {
+        let path = self.lease_path(room_id);
+        match std::fs::read_to_string(&path) {
+            Ok(holder) if holder == self.local.id => return Ok(true),
+            Ok(holder) => {
+                let members = self.members.read().unwrap();
+                if members.iter().any(|node| node.id == holder) {
+                    return Ok(false);
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("Could not read {}", path.display()))
+            }
+        }
+
+        let tmp_path = self.leases_dir.join(format!("{}.tmp", room_id));
+        std::fs::write(&tmp_path, &self.local.id)
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+
+        Ok(true)
+    }
+
+    /// Give up the lease on a room, once it has been saved and dropped.
+    pub fn release_lease(&self, room_id: &str) -> Result<()> {
+        let path = self.lease_path(room_id);
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Could not delete {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Release leases left behind by an earlier run of this node, which
+    /// holds no rooms when it starts.
+    fn release_stale_leases(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.leases_dir)
+            .with_context(|| format!("Could not read {}", self.leases_dir.display()))?
+        {
+            let path = entry?.path();
+            if std::fs::read_to_string(&path).ok().as_deref() == Some(self.local.id.as_str()) {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Could not delete {}", path.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn heartbeat_path(&self) -> PathBuf {
+        self.nodes_dir.join(format!("{}.json", self.local.id))
+    }
+
+    /// Announce that this node is alive.
+    fn heartbeat(&self) -> Result<()> {
+        let heartbeat = Heartbeat {
+            node: self.local.clone(),
+            timestamp: now(),
+        };
+
+        let path = self.heartbeat_path();
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&heartbeat)?)
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Read the heartbeats of all nodes, returning those which are live.
+    fn read_members(&self) -> Result<Vec<Node>> {
+        let oldest = now().saturating_sub(self.node_timeout);
+        let mut members = vec![self.local.clone()];
+
+        for entry in std::fs::read_dir(&self.nodes_dir)
+            .with_context(|| format!("Could not read {}", self.nodes_dir.display()))?
+        {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+
+            // A node may be removing or replacing its file as we read it.
+            let Ok(bytes) = std::fs::read(&path) else {
+                continue;
+            };
+            let heartbeat: Heartbeat = match serde_json::from_slice(&bytes) {
+                Ok(heartbeat) => heartbeat,
+                Err(err) => {
+                    tracing::warn!(?err, ?path, "Ignoring invalid heartbeat file.");
+                    continue;
+                }
+            };
+
+            if heartbeat.timestamp >= oldest && heartbeat.node.id != self.local.id {
+                members.push(heartbeat.node);
+            }
+        }
+
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(members)
+    }
+
+    /// Announce this node and refresh the list of live nodes.
+    pub fn refresh(&self) -> Result<()> {
+        self.heartbeat()?;
+        let members = self.read_members()?;
+
+        let mut current = self.members.write().unwrap();
+        if *current != members {
+            let ids: Vec<&str> = members.iter().map(|node| node.id.as_str()).collect();
+            tracing::info!(?ids, "Cluster membership changed.");
+            *current = members;
+        }
+
+        Ok(())
+    }
+
+    /// Keep membership up to date, handing off rooms which this node no
+    /// longer owns. Runs forever.
+    pub async fn run(self: Arc<Self>, rooms: Arc<Rooms>) {
+        let mut interval = tokio::time::interval(self.heartbeat_interval);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = self.refresh() {
+                tracing::error!(?err, "Error refreshing cluster membership.");
+            }
+
+            rooms.hand_off();
+        }
+    }
+
+    /// Remove this node's heartbeat, so that other nodes take over its
+    /// rooms without waiting for it to time out.
+    pub fn leave(&self) {
+        let path = self.heartbeat_path();
+        if let Err(err) = std::fs::remove_file(&path) {
+            tracing::error!(?err, ?path, "Error removing heartbeat file.");
+        }
+    }
+}
+
+fn room_id_from_path(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("/room/")?;
+    let room_id = rest.split('/').next()?;
+
+    (!room_id.is_empty()).then_some(room_id)
+}
+
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+/// Middleware which sends requests for rooms owned by other nodes to the
+/// owner: HTTP requests are redirected, and WebSocket connections (which
+/// browsers do not redirect) are proxied.
+pub async fn route_to_owner(
+    State((cluster, shutdown)): State<(Arc<Cluster>, ShutdownSignal)>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    // Clients can't skip routing by setting the header themselves.
+    let proxied = cluster.is_proxied(request.headers());
+    if !proxied {
+        request.headers_mut().remove(PROXIED_HEADER);
+    }
+
+    let owner = match room_id_from_path(request.uri().path()) {
+        Some(room_id) if !proxied => cluster.remote_owner(room_id),
+        _ => None,
+    };
+
+    let Some(owner) = owner else {
+        return next.run(request).await;
+    };
+
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or_default();
+    let url = format!("{}{}", owner.url, path_and_query);
+
+    if !is_websocket_upgrade(request.headers()) {
+        return Redirect::temporary(&url).into_response();
+    }
+
+    let url = url
+        .replacen("http://", "ws://", 1)
+        .replacen("https://", "wss://", 1);
+    let (mut parts, _) = request.into_parts();
+    let ws = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(ws) => ws,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let authorization = parts.headers.get(header::AUTHORIZATION);
+    let upstream = match connect_upstream(&url, &cluster.secret, authorization).await {
+        Ok(upstream) => upstream,
+        // Pass on errors from the owner, such as a 404 for a missing room.
+        Err(tungstenite::Error::Http(response)) => return response.status().into_response(),
+        Err(err) => {
+            tracing::error!(?err, ?url, "Error connecting to room owner.");
+            return (StatusCode::BAD_GATEWAY, "Could not reach the room's owner.").into_response();
+        }
+    };
+
+    ws.on_upgrade(move |socket| proxy_websocket(socket, upstream, shutdown))
+}
+
+type Upstream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Connect to the owner of a room on behalf of a client, passing on the
+/// client's credentials, e.g. for debug connections.
+async fn connect_upstream(
+    url: &str,
+    secret: &str,
+    authorization: Option<&header::HeaderValue>,
+) -> Result<Upstream, tungstenite::Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        PROXIED_HEADER,
+        header::HeaderValue::from_str(secret).expect("The secret is validated at startup."),
+    );
+    if let Some(authorization) = authorization {
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, authorization.clone());
+    }
+
+    let (upstream, _) = tokio_tungstenite::connect_async(request).await?;
+
+    Ok(upstream)
+}
+
+fn to_upstream(message: Message) -> tungstenite::Message {
+    match message {
+        Message::Text(text) => tungstenite::Message::Text(text),
+        Message::Binary(bytes) => tungstenite::Message::Binary(bytes),
+        Message::Ping(bytes) => tungstenite::Message::Ping(bytes),
+        Message::Pong(bytes) => tungstenite::Message::Pong(bytes),
+        Message::Close(frame) => {
+            tungstenite::Message::Close(frame.map(|frame| tungstenite::protocol::CloseFrame {
+                code: CloseCode::from(frame.code),
+                reason: frame.reason,
+            }))
+        }
+    }
+}
+
+fn from_upstream(message: tungstenite::Message) -> Option<Message> {
+    Some(match message {
+        tungstenite::Message::Text(text) => Message::Text(text),
+        tungstenite::Message::Binary(bytes) => Message::Binary(bytes),
+        tungstenite::Message::Ping(bytes) => Message::Ping(bytes),
+        tungstenite::Message::Pong(bytes) => Message::Pong(bytes),
+        tungstenite::Message::Close(frame) => Message::Close(frame.map(|frame| CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
+        tungstenite::Message::Frame(_) => return None,
+    })
+}
+
+/// Relay messages between a client and the node which owns its room, until
+/// either side closes the connection.
+async fn proxy_websocket(
+    mut client: WebSocket,
+    mut upstream: Upstream,
+    mut shutdown: ShutdownSignal,
+) {
+    loop {
+        tokio::select! {
+            message = client.recv() => {
+                let Some(Ok(message)) = message else {
+                    let _ = upstream.close(None).await;
+                    break;
+                };
+
+                let is_close = matches!(message, Message::Close(_));
+                if upstream.send(to_upstream(message)).await.is_err() || is_close {
+                    break;
+                }
+            }
+            message = upstream.next() => {
+                let Some(Ok(message)) = message else {
+                    let _ = client.close().await;
+                    break;
+                };
+
+                let Some(message) = from_upstream(message) else {
+                    continue;
+                };
+
+                let is_close = matches!(message, Message::Close(_));
+                if client.send(message).await.is_err() || is_close {
+                    break;
+                }
+            }
+            _ = shutdown.wait() => {
+                let _ = upstream.close(None).await;
+                let _ = client
+                    .send(Message::Close(Some(CloseFrame {
+                        code: crate::shutdown::CLOSE_CODE_SERVICE_RESTART,
+                        reason: format!("retry_after={}", shutdown.retry_after.as_secs()).into(),
+                    })))
+                    .await;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str) -> Node {
+        Node {
+            id: id.to_string(),
+            url: format!("http://{}:8080", id),
+        }
+    }
+
+    #[test]
+    fn test_owner_is_stable_when_nodes_change() {
+        let three = vec![node("a"), node("b"), node("c")];
+        let four = vec![node("a"), node("b"), node("c"), node("d")];
+
+        let rooms: Vec<String> = (0..1000).map(|i| format!("room-{}", i)).collect();
+
+        for room in &rooms {
+            let before = owner_of(&three, room).unwrap();
+            let after = owner_of(&four, room).unwrap();
+
+            // Rooms only move to the new node, never between existing ones.
+            assert!(after == before || after.id == "d");
+        }
+
+        let moved = rooms
+            .iter()
+            .filter(|room| owner_of(&four, room).unwrap().id == "d")
+            .count();
+        assert!((150..350).contains(&moved), "moved {} rooms", moved);
+    }
+
+    #[test]
+    fn test_membership() {
+        let dir = std::env::temp_dir().join(format!("driftdb-cluster-{}", uuid::Uuid::new_v4()));
+        let config = |node_id: &str| ClusterConfig {
+            node_id: node_id.to_string(),
+            advertise_url: format!("http://{}:8080/", node_id),
+            heartbeat_seconds: 1,
+            node_timeout_seconds: 10,
+            secret: "secret".to_string(),
+        };
+
+        let a = Cluster::new(&config("a"), &dir).unwrap();
+        let b = Cluster::new(&config("b"), &dir).unwrap();
+
+        a.refresh().unwrap();
+        assert!(a.is_local("room"));
+
+        b.refresh().unwrap();
+        a.refresh().unwrap();
+        assert_eq!(vec![node("a"), node("b")], *a.members.read().unwrap());
+        assert_eq!(a.owner("room"), b.owner("room"));
+
+        b.leave();
+        a.refresh().unwrap();
+        assert_eq!(vec![node("a")], *a.members.read().unwrap());
+
+        // Only requests carrying the secret count as proxied.
+        let mut headers = HeaderMap::new();
+        assert!(!a.is_proxied(&headers));
+        headers.insert(PROXIED_HEADER, header::HeaderValue::from_static("1"));
+        assert!(!a.is_proxied(&headers));
+        headers.insert(PROXIED_HEADER, header::HeaderValue::from_static("secret"));
+        assert!(a.is_proxied(&headers));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_leases() {
+        let dir = std::env::temp_dir().join(format!("driftdb-cluster-{}", uuid::Uuid::new_v4()));
+        let config = |node_id: &str| ClusterConfig {
+            node_id: node_id.to_string(),
+            advertise_url: format!("http://{}:8080/", node_id),
+            heartbeat_seconds: 1,
+            node_timeout_seconds: 10,
+            secret: "secret".to_string(),
+        };
+
+        let a = Cluster::new(&config("a"), &dir).unwrap();
+        let b = Cluster::new(&config("b"), &dir).unwrap();
+        a.refresh().unwrap();
+        b.refresh().unwrap();
+
+        // A live node's lease holds until it is released.
+        assert!(a.acquire_lease("room").unwrap());
+        assert!(a.acquire_lease("room").unwrap());
+        assert!(!b.acquire_lease("room").unwrap());
+        a.release_lease("room").unwrap();
+        assert!(b.acquire_lease("room").unwrap());
+
+        // Leases of a node which is gone are taken over.
+        b.leave();
+        a.refresh().unwrap();
+        assert!(a.acquire_lease("room").unwrap());
+
+        // A node releases its leases when it restarts.
+        Cluster::new(&config("a"), &dir).unwrap();
+        b.refresh().unwrap();
+        assert!(b.acquire_lease("room").unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_room_id_from_path() {
+        assert_eq!(Some("abc"), room_id_from_path("/room/abc"));
+        assert_eq!(Some("abc"), room_id_from_path("/room/abc/connect"));
+        assert_eq!(None, room_id_from_path("/room/"));
+        assert_eq!(None, room_id_from_path("/new"));
+    }
+}
@@ -0,0 +1,757 @@
+use crate::Opts;
+use anyhow::{anyhow, bail, Context, Result};
+use driftdb::types::validate_room_id;
+use hyper::{http::HeaderValue, Uri};
+use serde::{Deserialize, Deserializer};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Prefix of environment variables which override configuration values.
+/// Nested values are separated by a double underscore, e.g.
+/// `DRIFTDB_ROOMS__RETENTION_SECONDS=3600`.
+const ENV_PREFIX: &str = "DRIFTDB_";
+const ENV_SEPARATOR: &str = "__";
+
+/// Environment variable holding the path to the configuration file. This is
+/// read by the command line parser rather than treated as an override.
+pub const CONFIG_PATH_ENV: &str = "DRIFTDB_CONFIG";
+
+const DEFAULT_PORT: u16 = 8080;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to accept connections on.
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<SocketAddr>,
+
+    /// TLS certificate and key. If present, the server serves HTTPS and WSS.
+    pub tls: Option<TlsConfig>,
+
+    pub cors: CorsConfig,
+    pub rooms: RoomsConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub shutdown: ShutdownConfig,
+    pub webhooks: WebhooksConfig,
+
+    /// If present, the server runs as one node of a cluster.
+    pub cluster: Option<ClusterConfig>,
+
+    /// If present, the server follows rooms of another server.
+    pub replication: Option<ReplicationConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                DEFAULT_PORT,
+            )],
+            tls: None,
+            cors: CorsConfig::default(),
+            rooms: RoomsConfig::default(),
+            storage: StorageConfig::default(),
+            auth: AuthConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            cluster: None,
+            replication: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Path to a PEM-encoded certificate chain.
+    pub cert: PathBuf,
+
+    /// Path to a PEM-encoded private key.
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests. `*` allows any origin.
+    #[serde(deserialize_with = "one_or_many")]
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_string()],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Number of seconds a room is retained after its last activity once no
+    /// clients are connected to it.
+    pub retention_seconds: u64,
+
+    /// Create rooms that do not exist when they are first accessed, instead
+    /// of returning a 404 error.
+    pub create_on_access: bool,
+
+    /// Maximum number of rooms held by the server at once.
+    pub max_rooms: Option<usize>,
+
+    /// Maximum number of simultaneous WebSocket connections to one room.
+    pub max_connections_per_room: Option<usize>,
+
+    /// If present, rooms retain values which are removed from streams, so
+    /// that streams can be read as they were at an earlier point.
+    pub history: Option<HistoryConfig>,
+
+    /// Number of seconds a client can reconnect within to resume the
+    /// subscriptions of a lost connection. Zero disables resumption.
+    pub session_grace_seconds: u64,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        Self {
+            retention_seconds: 60 * 60 * 24,
+            create_on_access: false,
+            max_rooms: None,
+            max_connections_per_room: None,
+            history: None,
+            session_grace_seconds: 30,
+        }
+    }
+}
+
+impl RoomsConfig {
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_seconds)
+    }
+
+    pub fn session_grace(&self) -> Duration {
+        Duration::from_secs(self.session_grace_seconds)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Maximum number of removed values retained by each room.
+    pub max_values: usize,
+
+    /// If set, removed values are dropped once this many sequence numbers
+    /// have been assigned in the room since they were removed.
+    pub max_age: Option<u64>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_values: 1000,
+            max_age: None,
+        }
+    }
+}
+
+impl From<&HistoryConfig> for driftdb::HistoryConfig {
+    fn from(config: &HistoryConfig) -> Self {
+        Self {
+            max_values: config.max_values,
+            max_age: config.max_age,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "backend", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StorageConfig {
+    /// Rooms only live in memory, and are lost when the server exits.
+    #[default]
+    Memory,
+
+    /// Each room is periodically written to a file in the given directory,
+    /// and loaded from it when the room is next accessed.
+    Directory {
+        path: PathBuf,
+
+        /// How often modified rooms are written to disk.
+        #[serde(default = "default_flush_interval_seconds")]
+        flush_interval_seconds: u64,
+    },
+}
+
+fn default_flush_interval_seconds() -> u64 {
+    5
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Bearer tokens accepted for creating rooms. If empty, anyone may
+    /// create rooms.
+    #[serde(deserialize_with = "one_or_many")]
+    pub api_keys: Vec<String>,
+
+    /// Tokens accepted for debug connections, which can read every key of
+    /// a room. If empty, debug connections are refused.
+    #[serde(deserialize_with = "one_or_many")]
+    pub debug_keys: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Maximum number of seconds to wait for clients to disconnect when
+    /// shutting down.
+    pub timeout_seconds: u64,
+
+    /// Number of seconds clients are told to wait before reconnecting when
+    /// the server shuts down.
+    pub retry_seconds: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout_seconds: 10,
+            retry_seconds: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// Number of times delivery of an event to a webhook is attempted
+    /// before it is recorded as a dead letter.
+    pub max_attempts: u32,
+
+    /// Number of seconds to wait before the first retry. The delay doubles
+    /// after each further failure.
+    pub initial_backoff_seconds: u64,
+
+    /// Upper bound on the delay between retries, in seconds.
+    pub max_backoff_seconds: u64,
+
+    /// Number of seconds to wait for the target to respond to each attempt.
+    pub timeout_seconds: u64,
+
+    /// Number of failed deliveries kept per room for inspection.
+    pub dead_letter_limit: usize,
+
+    /// Number of deliveries which may wait to be sent. Changes arriving
+    /// while the queue is full are recorded as dead letters instead.
+    pub queue_size: usize,
+
+    /// Number of deliveries in progress at once, including their retries.
+    pub max_concurrent_deliveries: usize,
+
+    /// Allow webhooks to target loopback, link-local and private addresses.
+    /// Only enable this for local development.
+    pub allow_private_targets: bool,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_seconds: 1,
+            max_backoff_seconds: 60,
+            timeout_seconds: 10,
+            dead_letter_limit: 100,
+            queue_size: 1024,
+            max_concurrent_deliveries: 32,
+            allow_private_targets: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Name of this node, unique within the cluster.
+    pub node_id: String,
+
+    /// Base URL at which other nodes (and clients redirected to this node)
+    /// can reach it, e.g. `http://10.0.0.1:8080`.
+    pub advertise_url: String,
+
+    /// How often this node announces itself to the others, in seconds.
+    #[serde(default = "default_heartbeat_seconds")]
+    pub heartbeat_seconds: u64,
+
+    /// Number of seconds without a heartbeat after which a node is
+    /// considered to have left the cluster.
+    #[serde(default = "default_node_timeout_seconds")]
+    pub node_timeout_seconds: u64,
+
+    /// Secret shared by every node, with which nodes identify WebSocket
+    /// connections they proxy to each other.
+    pub secret: String,
+}
+
+fn default_heartbeat_seconds() -> u64 {
+    2
+}
+
+fn default_node_timeout_seconds() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Base URL of the server to replicate from, e.g. `http://10.0.0.1:8080`.
+    pub leader_url: String,
+
+    /// IDs of the rooms to replicate.
+    #[serde(deserialize_with = "one_or_many")]
+    pub rooms: Vec<String>,
+
+    /// Bearer token to send to the leader, if it requires an API key.
+    pub api_key: Option<String>,
+
+    /// Number of seconds to wait before reconnecting to the leader after
+    /// losing the connection.
+    #[serde(default = "default_reconnect_seconds")]
+    pub reconnect_seconds: u64,
+}
+
+fn default_reconnect_seconds() -> u64 {
+    1
+}
+
+/// Accept either a single value or a list of values, so that list settings
+/// can be given as a plain string in environment variables.
+fn one_or_many<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl Config {
+    /// Build the configuration from (in increasing order of precedence)
+    /// defaults, the configuration file, `DRIFTDB_*` environment variables,
+    /// and command line flags. The result is validated before returning.
+    pub fn load(opts: &Opts) -> Result<Config> {
+        let mut table = match &opts.config {
+            Some(path) => read_config_file(path)?,
+            None => toml::Table::new(),
+        };
+
+        apply_env_overrides(&mut table, std::env::vars())?;
+
+        let mut config: Config = toml::Value::Table(table)
+            .try_into()
+            .context("Could not interpret configuration")?;
+        config.apply_opts(opts);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_opts(&mut self, opts: &Opts) {
+        if opts.host.is_some() || opts.port.is_some() {
+            self.listen = vec![SocketAddr::new(
+                opts.host.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                opts.port.unwrap_or(DEFAULT_PORT),
+            )];
+        }
+
+        if let Some(retention_seconds) = opts.retention_seconds {
+            self.rooms.retention_seconds = retention_seconds;
+        }
+
+        if opts.create_rooms_on_access {
+            self.rooms.create_on_access = true;
+        }
+
+        if let Some(timeout_seconds) = opts.shutdown_timeout_seconds {
+            self.shutdown.timeout_seconds = timeout_seconds;
+        }
+
+        if let Some(retry_seconds) = opts.shutdown_retry_seconds {
+            self.shutdown.retry_seconds = retry_seconds;
+        }
+
+        if let (Some(cert), Some(key)) = (&opts.tls_cert, &opts.tls_key) {
+            self.tls = Some(TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+            });
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() {
+            bail!("`listen` must contain at least one address.");
+        }
+
+        if let Some(tls) = &self.tls {
+            for (name, path) in [("tls.cert", &tls.cert), ("tls.key", &tls.key)] {
+                if !path.is_file() {
+                    bail!("`{}` ({}) is not a readable file.", name, path.display());
+                }
+            }
+        }
+
+        if self.cors.allowed_origins.is_empty() {
+            bail!("`cors.allowed_origins` must contain at least one origin (or \"*\").");
+        }
+
+        for origin in &self.cors.allowed_origins {
+            if origin != "*" {
+                HeaderValue::from_str(origin).map_err(|_| {
+                    anyhow!(
+                        "`cors.allowed_origins` contains invalid origin {:?}.",
+                        origin
+                    )
+                })?;
+            }
+        }
+
+        if self.rooms.max_rooms == Some(0) {
+            bail!("`rooms.max_rooms` must be greater than zero.");
+        }
+
+        if self.rooms.max_connections_per_room == Some(0) {
+            bail!("`rooms.max_connections_per_room` must be greater than zero.");
+        }
+
+        if let Some(history) = &self.rooms.history {
+            if history.max_values == 0 {
+                bail!("`rooms.history.max_values` must be greater than zero.");
+            }
+        }
+
+        if let StorageConfig::Directory {
+            path,
+            flush_interval_seconds,
+        } = &self.storage
+        {
+            if *flush_interval_seconds == 0 {
+                bail!("`storage.flush_interval_seconds` must be greater than zero.");
+            }
+
+            if path.as_os_str().is_empty() {
+                bail!("`storage.path` must not be empty.");
+            }
+        }
+
+        if self.auth.api_keys.iter().any(|key| key.is_empty()) {
+            bail!("`auth.api_keys` must not contain empty keys.");
+        }
+
+        if self.auth.debug_keys.iter().any(|key| key.is_empty()) {
+            bail!("`auth.debug_keys` must not contain empty keys.");
+        }
+
+        if self.webhooks.max_attempts == 0 {
+            bail!("`webhooks.max_attempts` must be greater than zero.");
+        }
+
+        if self.webhooks.timeout_seconds == 0 {
+            bail!("`webhooks.timeout_seconds` must be greater than zero.");
+        }
+
+        if self.webhooks.queue_size == 0 {
+            bail!("`webhooks.queue_size` must be greater than zero.");
+        }
+
+        if self.webhooks.max_concurrent_deliveries == 0 {
+            bail!("`webhooks.max_concurrent_deliveries` must be greater than zero.");
+        }
+
+        if let Some(cluster) = &self.cluster {
+            if !matches!(self.storage, StorageConfig::Directory { .. }) {
+                bail!("`cluster` requires the `directory` storage backend, shared between nodes.");
+            }
+
+            // Node IDs are used as file names, like room IDs.
+            validate_room_id(&cluster.node_id)
+                .map_err(|err| anyhow!("`cluster.node_id` is invalid: {}", err))?;
+
+            let url: Uri = cluster
+                .advertise_url
+                .parse()
+                .context("`cluster.advertise_url` is not a valid URL")?;
+            if !matches!(url.scheme_str(), Some("http" | "https")) || url.host().is_none() {
+                bail!("`cluster.advertise_url` must be an absolute http or https URL.");
+            }
+
+            if cluster.heartbeat_seconds == 0 {
+                bail!("`cluster.heartbeat_seconds` must be greater than zero.");
+            }
+
+            if cluster.node_timeout_seconds <= cluster.heartbeat_seconds {
+                bail!("`cluster.node_timeout_seconds` must be greater than `cluster.heartbeat_seconds`.");
+            }
+
+            if cluster.secret.is_empty() || HeaderValue::from_str(&cluster.secret).is_err() {
+                bail!("`cluster.secret` must be a non-empty string of visible ASCII characters.");
+            }
+        }
+
+        if let Some(replication) = &self.replication {
+            if self.cluster.is_some() {
+                bail!("`replication` cannot be combined with `cluster`.");
+            }
+
+            let url: Uri = replication
+                .leader_url
+                .parse()
+                .context("`replication.leader_url` is not a valid URL")?;
+            if !matches!(url.scheme_str(), Some("http" | "https")) || url.host().is_none() {
+                bail!("`replication.leader_url` must be an absolute http or https URL.");
+            }
+
+            if replication.rooms.is_empty() {
+                bail!("`replication.rooms` must contain at least one room.");
+            }
+
+            for room_id in &replication.rooms {
+                validate_room_id(room_id).map_err(|err| {
+                    anyhow!(
+                        "`replication.rooms` contains invalid room {:?}: {}",
+                        room_id,
+                        err
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<toml::Table> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read configuration file {}", path.display()))?;
+
+    toml::from_str(&contents)
+        .with_context(|| format!("Could not parse configuration file {}", path.display()))
+}
+
+/// Overlay `DRIFTDB_*` environment variables onto the configuration table.
+/// Values are parsed as TOML where possible (so numbers, booleans and lists
+/// work), and are otherwise taken as plain strings.
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    for (name, value) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        if name == CONFIG_PATH_ENV {
+            continue;
+        }
+
+        let path: Vec<String> = path
+            .split(ENV_SEPARATOR)
+            .map(|part| part.to_lowercase())
+            .collect();
+
+        if path.iter().any(|part| part.is_empty()) {
+            bail!("Invalid configuration environment variable {}.", name);
+        }
+
+        let value = parse_env_value(&value);
+
+        let (last, parents) = path.split_last().expect("split always yields a part");
+        let mut current = &mut *table;
+        for part in parents {
+            let entry = current
+                .entry(part.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+
+            current = match entry {
+                toml::Value::Table(table) => table,
+                _ => bail!(
+                    "Environment variable {} overrides a value which is not a table.",
+                    name
+                ),
+            };
+        }
+
+        current.insert(last.clone(), value);
+    }
+
+    Ok(())
+}
+
+fn parse_env_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str, env: &[(&str, &str)]) -> Result<Config> {
+        let mut table: toml::Table = toml::from_str(contents)?;
+        let vars = env.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        apply_env_overrides(&mut table, vars)?;
+
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Config::default(), parse("", &[]).unwrap());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = parse(
+            r#"
+            listen = ["0.0.0.0:8080", "[::]:8080"]
+
+            [cors]
+            allowed_origins = ["https://example.com"]
+
+            [rooms]
+            retention_seconds = 60
+            max_rooms = 100
+
+            [storage]
+            backend = "directory"
+            path = "/var/lib/driftdb"
+
+            [auth]
+            api_keys = ["secret"]
+            "#,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(2, config.listen.len());
+        assert_eq!(vec!["https://example.com"], config.cors.allowed_origins);
+        assert_eq!(60, config.rooms.retention_seconds);
+        assert_eq!(Some(100), config.rooms.max_rooms);
+        assert_eq!(
+            StorageConfig::Directory {
+                path: "/var/lib/driftdb".into(),
+                flush_interval_seconds: 5,
+            },
+            config.storage
+        );
+        assert_eq!(vec!["secret"], config.auth.api_keys);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let config = parse(
+            r#"
+            [rooms]
+            retention_seconds = 60
+            "#,
+            &[
+                ("DRIFTDB_ROOMS__RETENTION_SECONDS", "120"),
+                ("DRIFTDB_ROOMS__CREATE_ON_ACCESS", "true"),
+                ("DRIFTDB_LISTEN", "0.0.0.0:9000"),
+                ("DRIFTDB_AUTH__API_KEYS", "my-key"),
+                ("DRIFTDB_CONFIG", "ignored.toml"),
+                ("UNRELATED", "ignored"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(120, config.rooms.retention_seconds);
+        assert!(config.rooms.create_on_access);
+        assert_eq!(
+            vec!["0.0.0.0:9000".parse::<SocketAddr>().unwrap()],
+            config.listen
+        );
+        assert_eq!(vec!["my-key"], config.auth.api_keys);
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        assert!(parse("[rooms]\nretention = 60", &[]).is_err());
+        assert!(parse("", &[("DRIFTDB_NOPE", "1")]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+
+        config.rooms.max_rooms = Some(0);
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.cors.allowed_origins = vec!["bad\norigin".to_string()];
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.webhooks.max_attempts = 0;
+        assert!(config.validate().is_err());
+
+        // Clustering requires storage shared between nodes.
+        let config = Config {
+            cluster: Some(ClusterConfig {
+                node_id: "a".to_string(),
+                advertise_url: "http://10.0.0.1:8080".to_string(),
+                heartbeat_seconds: 2,
+                node_timeout_seconds: 10,
+                secret: "secret".to_string(),
+            }),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let replication = ReplicationConfig {
+            leader_url: "http://10.0.0.1:8080".to_string(),
+            rooms: vec!["important".to_string()],
+            api_key: None,
+            reconnect_seconds: 1,
+        };
+        let config = Config {
+            replication: Some(replication.clone()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = Config {
+            replication: Some(ReplicationConfig {
+                leader_url: "10.0.0.1:8080".to_string(),
+                ..replication.clone()
+            }),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            replication: Some(ReplicationConfig {
+                rooms: vec![],
+                ..replication
+            }),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
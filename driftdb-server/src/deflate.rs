@@ -0,0 +1,656 @@
+//! The `permessage-deflate` WebSocket extension ([RFC 7692]), which
+//! tungstenite doesn't implement.
+//!
+//! [Deflate] sits between the connection and tungstenite, inflating
+//! compressed messages from the client and compressing large messages to it,
+//! so that tungstenite only ever sees uncompressed frames. Both directions
+//! are negotiated without context takeover, so each message is compressed on
+//! its own and no compression state outlives a message. Large messages are
+//! compressed on a blocking thread, so that they don't hold up the executor.
+//!
+//! [RFC 7692]: https://www.rfc-editor.org/rfc/rfc7692
+
+use axum::{
+    async_trait,
+    body::{boxed, Empty},
+    extract::FromRequestParts,
+    http::request::Parts,
+    response::Response,
+};
+use flate2::{write::DeflateEncoder, Decompress, FlushDecompress};
+use hyper::{
+    header,
+    upgrade::{OnUpgrade, Upgraded},
+    HeaderMap, Method, StatusCode,
+};
+use std::{
+    future::Future,
+    io::{self, Cursor, Write},
+    ops::Range,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{
+            frame::{
+                coding::{Data, OpCode},
+                Frame, FrameHeader,
+            },
+            Role,
+        },
+    },
+    WebSocketStream,
+};
+
+/// Name of the extension in the `Sec-WebSocket-Extensions` header.
+const EXTENSION: &str = "permessage-deflate";
+
+/// The extension parameters the server responds with.
+const ACCEPTED_EXTENSION: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Bytes which end every compressed message, and which senders strip.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Upper bound on the size of a message, compressed or not. Matches
+/// tungstenite's default.
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Messages to the client of at least this many bytes are compressed on a
+/// blocking thread rather than on the executor.
+const BLOCKING_COMPRESSION_SIZE: usize = 64 * 1024;
+
+/// A WebSocket connection which may be using `permessage-deflate`.
+pub type WebSocket = WebSocketStream<Deflate<Upgraded>>;
+
+/// Whether any of the client's offers of the extension can be accepted.
+fn accepts_offer(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            params.next() == Some(EXTENSION)
+                && params.all(|param| {
+                    let (name, value) = match param.split_once('=') {
+                        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                        None => (param, None),
+                    };
+                    match name {
+                        "server_no_context_takeover"
+                        | "client_no_context_takeover"
+                        | "client_max_window_bits" => true,
+                        // Outgoing messages are always compressed with the
+                        // largest window.
+                        "server_max_window_bits" => value == Some("15"),
+                        _ => false,
+                    }
+                })
+        })
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Find the first whole frame in `buffer`, returning its header and the
+/// range of its payload. The payload is still masked, if the frame is.
+fn next_frame(buffer: &[u8]) -> io::Result<Option<(FrameHeader, Range<usize>)>> {
+    let mut cursor = Cursor::new(buffer);
+    let Some((header, length)) = FrameHeader::parse(&mut cursor).map_err(invalid_data)? else {
+        return Ok(None);
+    };
+
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err(invalid_data("WebSocket frame is too large."));
+    }
+
+    let start = cursor.position() as usize;
+    let end = start + length as usize;
+    Ok((end <= buffer.len()).then_some((header, start..end)))
+}
+
+fn unmask(payload: &mut [u8], mask: Option<[u8; 4]>) {
+    if let Some(mask) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+}
+
+fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    // Flushing ends the output on a byte boundary, followed by the trailer.
+    encoder.flush()?;
+
+    let mut compressed = std::mem::take(encoder.get_mut());
+    if !compressed.ends_with(&TRAILER) {
+        return Err(invalid_data("Compressed message is missing its trailer."));
+    }
+    compressed.truncate(compressed.len() - TRAILER.len());
+
+    Ok(compressed)
+}
+
+fn inflate(mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+    data.extend_from_slice(&TRAILER);
+
+    // The message doesn't end the deflate stream, which rules out
+    // flate2's readers.
+    let mut decompress = Decompress::new(false);
+    let mut message = Vec::with_capacity(data.len() * 2);
+    loop {
+        if message.len() == message.capacity() {
+            if message.len() > MAX_MESSAGE_SIZE {
+                return Err(invalid_data("Decompressed message is too large."));
+            }
+            message.reserve(message.len().min(MAX_MESSAGE_SIZE + 1 - message.len()));
+        }
+
+        let total_in = decompress.total_in() as usize;
+        decompress
+            .decompress_vec(&data[total_in..], &mut message, FlushDecompress::Sync)
+            .map_err(invalid_data)?;
+
+        // Output stops short of filling the buffer once the input is used up.
+        if message.len() < message.capacity() {
+            if (decompress.total_in() as usize) < data.len() {
+                return Err(invalid_data("Compressed message is truncated."));
+            }
+            return Ok(message);
+        }
+    }
+}
+
+/// A connection on which `permessage-deflate` may have been negotiated.
+pub struct Deflate<S> {
+    inner: S,
+
+    /// Messages smaller than this many bytes are sent uncompressed. `None`
+    /// if the extension was not negotiated, in which case frames are passed
+    /// through untouched.
+    threshold: Option<usize>,
+
+    /// Bytes read from the connection which don't yet make up a whole frame.
+    incoming: Vec<u8>,
+
+    /// Frames ready to be read by tungstenite, from `readable_pos` on.
+    readable: Vec<u8>,
+    readable_pos: usize,
+
+    /// The opcode and payload so far of a compressed message from the client
+    /// which is still missing frames.
+    partial: Option<(OpCode, Vec<u8>)>,
+
+    /// Bytes written by tungstenite which don't yet make up a whole frame.
+    outgoing: Vec<u8>,
+
+    /// Frames ready to be written to the connection.
+    writable: Vec<u8>,
+
+    /// Compression of the first frame in `outgoing`, if it is large enough
+    /// to happen on a blocking thread.
+    compressing: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl<S> Deflate<S> {
+    pub fn new(inner: S, threshold: Option<usize>) -> Self {
+        Self {
+            inner,
+            threshold,
+            incoming: Vec::new(),
+            readable: Vec::new(),
+            readable_pos: 0,
+            partial: None,
+            outgoing: Vec::new(),
+            writable: Vec::new(),
+            compressing: None,
+        }
+    }
+
+    /// Move the first whole frame from `incoming` to `readable`, inflating
+    /// it if it completes a compressed message. Returns false if there is no
+    /// whole frame.
+    fn process_incoming(&mut self) -> io::Result<bool> {
+        let Some((header, payload)) = next_frame(&self.incoming)? else {
+            return Ok(false);
+        };
+        let end = payload.end;
+
+        let compressed = self.threshold.is_some()
+            && match header.opcode {
+                OpCode::Data(Data::Text | Data::Binary) if self.partial.is_some() => {
+                    return Err(invalid_data("Expected a continuation frame."));
+                }
+                OpCode::Data(Data::Text | Data::Binary) => header.rsv1,
+                OpCode::Data(Data::Continue) => self.partial.is_some(),
+                _ => false,
+            };
+
+        if compressed {
+            let (_, data) = self
+                .partial
+                .get_or_insert_with(|| (header.opcode, Vec::new()));
+            let start = data.len();
+            data.extend_from_slice(&self.incoming[payload]);
+            unmask(&mut data[start..], header.mask);
+
+            if data.len() > MAX_MESSAGE_SIZE {
+                return Err(invalid_data("WebSocket message is too large."));
+            }
+
+            if header.is_final {
+                if let Some((opcode, data)) = self.partial.take() {
+                    let header = FrameHeader {
+                        opcode,
+                        // tungstenite expects frames from clients to be masked.
+                        mask: Some([0; 4]),
+                        ..FrameHeader::default()
+                    };
+                    Frame::from_payload(header, inflate(data)?)
+                        .format(&mut self.readable)
+                        .map_err(invalid_data)?;
+                }
+            }
+        } else {
+            self.readable.extend_from_slice(&self.incoming[..end]);
+        }
+
+        self.incoming.drain(..end);
+        Ok(true)
+    }
+
+    /// Move the first whole frame from `outgoing` to `writable`, compressing
+    /// it if it is a large enough message. Returns false if there is no
+    /// whole frame, and is pending while a large frame is being compressed.
+    fn process_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        let Some((header, payload)) = next_frame(&self.outgoing)? else {
+            return Poll::Ready(Ok(false));
+        };
+        let end = payload.end;
+
+        // Fragmented messages are rare enough to be sent as they are.
+        let compress = match self.threshold {
+            Some(threshold) => {
+                matches!(header.opcode, OpCode::Data(Data::Text | Data::Binary))
+                    && header.is_final
+                    && header.mask.is_none()
+                    && payload.len() >= threshold
+            }
+            None => false,
+        };
+
+        let compressed = if !compress {
+            None
+        } else if payload.len() >= BLOCKING_COMPRESSION_SIZE {
+            let task = self.compressing.get_or_insert_with(|| {
+                let data = self.outgoing[payload.clone()].to_vec();
+                tokio::task::spawn_blocking(move || deflate(&data))
+            });
+            let compressed = ready!(Pin::new(task).poll(cx));
+            self.compressing = None;
+            Some(compressed.map_err(invalid_data)??)
+        } else {
+            Some(deflate(&self.outgoing[payload.clone()])?)
+        };
+        let compressed = compressed.filter(|compressed| compressed.len() < payload.len());
+
+        match compressed {
+            Some(compressed) => {
+                let header = FrameHeader {
+                    rsv1: true,
+                    ..header
+                };
+                Frame::from_payload(header, compressed)
+                    .format(&mut self.writable)
+                    .map_err(invalid_data)?;
+            }
+            None => self.writable.extend_from_slice(&self.outgoing[..end]),
+        }
+
+        self.outgoing.drain(..end);
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Deflate<S> {
+    /// Process and write every whole frame written so far.
+    fn poll_write_frames(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_write_pending(cx))?;
+            if !ready!(self.process_outgoing(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.writable.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.writable))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.writable.drain(..written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deflate<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.readable_pos < this.readable.len() {
+                let available = &this.readable[this.readable_pos..];
+                let len = available.len().min(buf.remaining());
+                buf.put_slice(&available[..len]);
+
+                this.readable_pos += len;
+                if this.readable_pos == this.readable.len() {
+                    this.readable.clear();
+                    this.readable_pos = 0;
+                }
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.process_incoming()? {
+                continue;
+            }
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // The connection has closed.
+                return Poll::Ready(Ok(()));
+            }
+            this.incoming.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflate<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frames(cx))?;
+
+        this.outgoing.extend_from_slice(buf);
+
+        // Start on the new frames now; any left over, e.g. while a large
+        // one is compressed, are finished when tungstenite flushes.
+        loop {
+            match this.process_outgoing(cx) {
+                Poll::Ready(Ok(true)) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(false)) | Poll::Pending => break,
+            }
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frames(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frames(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Like axum's `WebSocketUpgrade`, but accepts `permessage-deflate` when the
+/// client offers it.
+pub struct WebSocketUpgrade {
+    key: header::HeaderValue,
+    deflate: bool,
+    on_upgrade: OnUpgrade,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for WebSocketUpgrade {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if parts.method != Method::GET {
+            return Err((
+                StatusCode::METHOD_NOT_ALLOWED,
+                "WebSocket requests must use GET.",
+            ));
+        }
+
+        let has_token = |name, token: &str| {
+            parts
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        if !has_token(header::CONNECTION, "upgrade") || !has_token(header::UPGRADE, "websocket") {
+            return Err((StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade."));
+        }
+
+        if parts.headers.get(header::SEC_WEBSOCKET_VERSION) != Some(&"13".parse().unwrap()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Only WebSocket version 13 is supported.",
+            ));
+        }
+
+        let key = parts
+            .headers
+            .get(header::SEC_WEBSOCKET_KEY)
+            .cloned()
+            .ok_or((StatusCode::BAD_REQUEST, "Missing Sec-WebSocket-Key header."))?;
+
+        let on_upgrade = parts.extensions.remove::<OnUpgrade>().ok_or((
+            StatusCode::UPGRADE_REQUIRED,
+            "This connection can't be upgraded.",
+        ))?;
+
+        Ok(Self {
+            key,
+            deflate: accepts_offer(&parts.headers),
+            on_upgrade,
+        })
+    }
+}
+
+impl WebSocketUpgrade {
+    /// Accept the upgrade, and call `callback` with the socket once the
+    /// connection has been upgraded. If `permessage-deflate` was negotiated,
+    /// messages of at least `threshold` bytes are compressed.
+    pub fn on_upgrade<F, Fut>(self, threshold: usize, callback: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let Self {
+            key,
+            deflate,
+            on_upgrade,
+        } = self;
+
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    tracing::warn!(?err, "WebSocket upgrade failed.");
+                    return;
+                }
+            };
+
+            let stream = Deflate::new(upgraded, deflate.then_some(threshold));
+            let socket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+            callback(socket).await;
+        });
+
+        let mut response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(
+                header::SEC_WEBSOCKET_ACCEPT,
+                derive_accept_key(key.as_bytes()),
+            );
+        if deflate {
+            response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, ACCEPTED_EXTENSION);
+        }
+
+        response
+            .body(boxed(Empty::new()))
+            .expect("Upgrade responses should be valid.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::Control;
+
+    fn offer(value: &'static str) -> bool {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            header::HeaderValue::from_static(value),
+        );
+        accepts_offer(&headers)
+    }
+
+    fn frame(opcode: OpCode, rsv1: bool, is_final: bool, mask: bool, payload: &[u8]) -> Vec<u8> {
+        let header = FrameHeader {
+            is_final,
+            rsv1,
+            opcode,
+            mask: mask.then_some([1, 2, 3, 4]),
+            ..FrameHeader::default()
+        };
+        let mut bytes = Vec::new();
+        Frame::from_payload(header, payload.to_vec())
+            .format(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_accepts_offer() {
+        assert!(offer("permessage-deflate"));
+        assert!(offer("permessage-deflate; client_max_window_bits"));
+        assert!(offer(
+            "x-other, permessage-deflate; server_max_window_bits=15"
+        ));
+        assert!(!offer("permessage-deflate; server_max_window_bits=10"));
+        assert!(!offer("permessage-deflate; unknown"));
+        assert!(!offer("x-other"));
+    }
+
+    #[tokio::test]
+    async fn test_deflate() {
+        let text = "a".repeat(2000).into_bytes();
+        let compressed = deflate(&text).unwrap();
+        assert!(compressed.len() < text.len());
+        assert_eq!(text, inflate(compressed.clone()).unwrap());
+
+        // A compressed message from the client, split across two frames.
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let mut input = frame(OpCode::Data(Data::Text), true, false, true, first);
+        input.extend(frame(
+            OpCode::Control(Control::Ping),
+            false,
+            true,
+            true,
+            b"ping",
+        ));
+        input.extend(frame(
+            OpCode::Data(Data::Continue),
+            false,
+            true,
+            true,
+            second,
+        ));
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut stream = Deflate::new(server, Some(1024));
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        client_write.write_all(&input).await.unwrap();
+        drop(client_write);
+
+        // The ping is passed through ahead of the inflated message.
+        let mut expected = frame(OpCode::Control(Control::Ping), false, true, true, b"ping");
+        let header = FrameHeader {
+            opcode: OpCode::Data(Data::Text),
+            mask: Some([0; 4]),
+            ..FrameHeader::default()
+        };
+        Frame::from_payload(header, text.clone())
+            .format(&mut expected)
+            .unwrap();
+        let mut read = vec![0; expected.len()];
+        stream.read_exact(&mut read).await.unwrap();
+        assert_eq!(expected, read);
+
+        // Large messages to the client are compressed, and small ones aren't.
+        let small = frame(OpCode::Data(Data::Text), false, true, false, b"small");
+        stream
+            .write_all(&frame(OpCode::Data(Data::Text), false, true, false, &text))
+            .await
+            .unwrap();
+        stream.write_all(&small).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut expected = frame(OpCode::Data(Data::Text), true, true, false, &compressed);
+        expected.extend(small.clone());
+        let mut written = vec![0; expected.len()];
+        client_read.read_exact(&mut written).await.unwrap();
+        assert_eq!(expected, written);
+
+        // Messages compressed on a blocking thread keep their place.
+        let large = "b".repeat(BLOCKING_COMPRESSION_SIZE).into_bytes();
+        stream
+            .write_all(&frame(OpCode::Data(Data::Text), false, true, false, &large))
+            .await
+            .unwrap();
+        stream.write_all(&small).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut expected = frame(
+            OpCode::Data(Data::Text),
+            true,
+            true,
+            false,
+            &deflate(&large).unwrap(),
+        );
+        expected.extend(small);
+        let mut written = vec![0; expected.len()];
+        client_read.read_exact(&mut written).await.unwrap();
+        assert_eq!(expected, written);
+    }
+}
@@ -0,0 +1,258 @@
+//! Server-Sent Events subscriptions, for clients which can receive a
+//! stream of messages from a room but cannot hold a WebSocket open.
+
+use crate::{
+    room::{ConnectionGuard, RoomError, Rooms},
+    shutdown::ShutdownSignal,
+};
+use axum::{
+    extract::{Path, RawQuery, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use driftdb::{
+    types::SequenceNumber, AsyncConnection, Key, MessageFromDatabase, MessageToDatabase,
+};
+use hyper::HeaderMap;
+use std::{convert::Infallible, sync::Arc};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// The keys to subscribe to, and the sequence number to resume from.
+#[derive(Debug, PartialEq)]
+struct EventsQuery {
+    keys: Vec<Key>,
+    seq: SequenceNumber,
+}
+
+impl EventsQuery {
+    /// Parse a query string like `key=a&key=b&seq=3`.
+    fn parse(query: &str) -> Result<Self, RoomError> {
+        let pairs: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|e| RoomError::BadRequest(e.to_string()))?;
+
+        let mut keys = Vec::new();
+        let mut seq = SequenceNumber::default();
+
+        for (name, value) in pairs {
+            match name.as_str() {
+                "key" => keys.push(Key::new(value)),
+                "seq" => seq = parse_seq(&value)?,
+                _ => {}
+            }
+        }
+
+        if keys.is_empty() {
+            return Err(RoomError::BadRequest(
+                "At least one key is required.".to_string(),
+            ));
+        }
+
+        Ok(EventsQuery { keys, seq })
+    }
+}
+
+fn parse_seq(value: &str) -> Result<SequenceNumber, RoomError> {
+    value
+        .trim()
+        .parse()
+        .map(SequenceNumber)
+        .map_err(|_| RoomError::BadRequest(format!("Invalid sequence number: {}", value)))
+}
+
+/// The position a reconnecting client can resume from via `Last-Event-ID`.
+struct Cursor {
+    /// Highest sequence number seen so far.
+    seq: SequenceNumber,
+
+    /// Number of requested keys whose initial `init` message has not been
+    /// sent yet. Until it reaches zero, resuming from `seq` could skip older
+    /// values of those keys, so events carry no ID.
+    pending_inits: usize,
+}
+
+impl Cursor {
+    /// Advance the cursor past a message, returning the event ID to give it.
+    fn advance(&mut self, message: &MessageFromDatabase) -> Option<SequenceNumber> {
+        let seq = match message {
+            MessageFromDatabase::Push { seq, .. } => Some(*seq),
+            MessageFromDatabase::Init { data, .. } => {
+                self.pending_inits = self.pending_inits.saturating_sub(1);
+                data.iter().map(|value| value.seq).max()
+            }
+            _ => None,
+        };
+
+        if let Some(seq) = seq {
+            self.seq = self.seq.max(seq);
+        }
+
+        (seq.is_some() && self.pending_inits == 0).then_some(self.seq)
+    }
+}
+
+/// Convert a message into an event. Events which carry data from the room
+/// have their ID set to the highest sequence number seen so far, once every
+/// key has been initialized, so that a reconnecting client can resume where
+/// it left off via `Last-Event-ID`.
+fn to_event(message: &MessageFromDatabase, cursor: &mut Cursor) -> Event {
+    let event = Event::default()
+        .json_data(message)
+        .expect("Messages should serialize as JSON.");
+
+    match cursor.advance(message) {
+        Some(seq) => event.id(seq.0.to_string()),
+        None => event,
+    }
+}
+
+async fn forward_events(
+    mut conn: AsyncConnection,
+    _guard: ConnectionGuard,
+    sender: mpsc::Sender<Result<Event, Infallible>>,
+    mut cursor: Cursor,
+    mut shutdown: ShutdownSignal,
+    mut closed: ShutdownSignal,
+) {
+    loop {
+        tokio::select! {
+            msg = conn.next() => {
+                // The stream only ends if the client fell too far behind. End
+                // the event stream too, rather than skip messages, so that the
+                // client resumes from the last event it received.
+                let Some(msg) = msg else {
+                    tracing::warn!("Client fell behind; closing event stream.");
+                    break;
+                };
+
+                if sender.send(Ok(to_event(&msg, &mut cursor))).await.is_err() {
+                    break;
+                }
+            }
+            _ = sender.closed() => {
+                // The client has gone away.
+                break;
+            }
+            retry_after = async {
+                tokio::select! {
+                    _ = shutdown.wait() => shutdown.retry_after,
+                    _ = closed.wait() => closed.retry_after,
+                }
+            } => {
+                // The server is shutting down, or has handed the room off to
+                // another node. Flush messages which are already queued for this
+                // client, then tell it when to reconnect.
+
+                while let Some(msg) = conn.try_recv() {
+                    if sender.send(Ok(to_event(&msg, &mut cursor))).await.is_err() {
+                        return;
+                    }
+                }
+
+                let _ = sender
+                    .send(Ok(Event::default().retry(retry_after)))
+                    .await;
+
+                break;
+            }
+        }
+    }
+}
+
+pub async fn events(
+    Path(room_id): Path<String>,
+    RawQuery(query): RawQuery,
+    State(rooms): State<Arc<Rooms>>,
+    State(shutdown): State<ShutdownSignal>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, RoomError> {
+    let mut query = EventsQuery::parse(query.as_deref().unwrap_or_default())?;
+
+    if let Some(last_event_id) = headers.get(LAST_EVENT_ID) {
+        let last_event_id = last_event_id
+            .to_str()
+            .map_err(|_| RoomError::BadRequest("Invalid Last-Event-ID.".to_string()))?;
+        query.seq = parse_seq(last_event_id)?;
+    }
+
+    let (room, guard) = rooms.connect(&room_id)?;
+
+    let conn = room.database.connect_async();
+    let closed = room.closed();
+    let cursor = Cursor {
+        seq: query.seq,
+        pending_inits: query.keys.len(),
+    };
+
+    for key in query.keys {
+        conn.send(&MessageToDatabase::Get {
+            key,
+            seq: Some(query.seq),
+            request_id: None,
+        })
+        .await
+        .map_err(RoomError::Database)?;
+    }
+
+    let (event_sender, event_receiver) = mpsc::channel(32);
+    tokio::spawn(forward_events(
+        conn,
+        guard,
+        event_sender,
+        cursor,
+        shutdown,
+        closed,
+    ));
+
+    Ok(Sse::new(ReceiverStream::new(event_receiver)).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ciborium::value::Value;
+    use driftdb::types::SequenceValue;
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(
+            EventsQuery {
+                keys: vec![Key::new("a".to_string()), Key::new("b c".to_string())],
+                seq: SequenceNumber(4),
+            },
+            EventsQuery::parse("key=a&seq=4&key=b%20c").unwrap()
+        );
+
+        assert_eq!(SequenceNumber(0), EventsQuery::parse("key=a").unwrap().seq);
+
+        assert!(EventsQuery::parse("seq=4").is_err());
+        assert!(EventsQuery::parse("key=a&seq=x").is_err());
+    }
+
+    #[test]
+    fn test_cursor_waits_for_every_init() {
+        let init = |key: &str, seq: u64| MessageFromDatabase::Init {
+            key: Key::new(key.to_string()),
+            data: vec![SequenceValue {
+                value: Value::Integer(seq.into()),
+                seq: SequenceNumber(seq),
+            }],
+        };
+        let mut cursor = Cursor {
+            seq: SequenceNumber(0),
+            pending_inits: 2,
+        };
+
+        assert_eq!(None, cursor.advance(&init("a", 10)));
+        assert_eq!(
+            None,
+            cursor.advance(&MessageFromDatabase::Push {
+                key: Key::new("a".to_string()),
+                value: Value::Null,
+                seq: SequenceNumber(11),
+            })
+        );
+        assert_eq!(Some(SequenceNumber(11)), cursor.advance(&init("b", 5)));
+    }
+}
@@ -0,0 +1,183 @@
+//! REST-style access to individual keys of a room, for clients which just
+//! want to read or write a value without speaking the message protocol.
+
+use crate::{
+    room::{RoomError, Rooms},
+    shutdown::ShutdownSignal,
+};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use ciborium::value::Value;
+use driftdb::{
+    types::{Action, SequenceNumber, SequenceValue},
+    Key, MessageFromDatabase, MessageToDatabase,
+};
+use hyper::{
+    header::{self, HeaderMap, HeaderValue},
+    StatusCode,
+};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Upper bound on how long a read may wait for new values.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+pub struct ReadKeyQuery {
+    /// Only return values with a sequence number greater than this.
+    #[serde(default)]
+    seq: u64,
+
+    /// If there are no values newer than `seq`, wait up to this many
+    /// seconds for one to arrive before responding.
+    #[serde(default)]
+    wait: u64,
+}
+
+#[derive(Deserialize)]
+pub struct WriteKeyQuery {
+    /// Name of the action to apply (defaults to `replace`).
+    action: Option<String>,
+
+    /// Sequence number, required by the `compact` action.
+    seq: Option<u64>,
+}
+
+fn header_contains(headers: &HeaderMap, name: header::HeaderName, needle: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains(needle))
+        .unwrap_or(false)
+}
+
+/// Return the values of `key` newer than `seq`. If there are none, wait for
+/// up to `timeout` for one to be pushed.
+async fn wait_for_values(
+    rooms: &Rooms,
+    room_id: &str,
+    key: Key,
+    seq: SequenceNumber,
+    timeout: Duration,
+    mut shutdown: ShutdownSignal,
+) -> Result<Vec<SequenceValue>, RoomError> {
+    let (room, _guard) = rooms.connect(room_id)?;
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let conn = room.database.connect(move |message| {
+        if let MessageFromDatabase::Push { value, seq, .. } = message {
+            let _ = sender.send(SequenceValue {
+                value: value.clone(),
+                seq: *seq,
+            });
+        }
+    });
+
+    // Subscribing and reading happen under the same lock, so no value can
+    // slip in between the two.
+    let init = conn
+        .send_message(&MessageToDatabase::Get {
+            key,
+            seq: Some(seq),
+            request_id: None,
+        })
+        .unwrap();
+
+    if let Some(MessageFromDatabase::Init { data, .. }) = init {
+        if !data.is_empty() {
+            return Ok(data);
+        }
+    }
+
+    let mut values = Vec::new();
+
+    tokio::select! {
+        Some(value) = receiver.recv() => values.push(value),
+        _ = tokio::time::sleep(timeout) => {},
+        _ = shutdown.wait() => {},
+    }
+
+    // Include anything else which arrived at the same time.
+    while let Ok(value) = receiver.try_recv() {
+        values.push(value);
+    }
+
+    Ok(values)
+}
+
+pub async fn read_key(
+    Path((room_id, key)): Path<(String, String)>,
+    Query(query): Query<ReadKeyQuery>,
+    State(rooms): State<Arc<Rooms>>,
+    State(shutdown): State<ShutdownSignal>,
+    headers: HeaderMap,
+) -> Result<Response, RoomError> {
+    let key = Key::new(key);
+    let seq = SequenceNumber(query.seq);
+
+    let values = if query.wait > 0 {
+        let timeout = Duration::from_secs(query.wait).min(MAX_WAIT);
+        wait_for_values(&rooms, &room_id, key, seq, timeout, shutdown).await?
+    } else {
+        rooms.get(&room_id)?.database.get(&key, seq)
+    };
+
+    if header_contains(&headers, header::ACCEPT, CBOR_CONTENT_TYPE) {
+        let mut body = Vec::new();
+        ciborium::ser::into_writer(&values, &mut body)
+            .expect("Encoding values as CBOR should not fail.");
+
+        Ok((
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(CBOR_CONTENT_TYPE),
+            )],
+            body,
+        )
+            .into_response())
+    } else {
+        Ok(Json(values).into_response())
+    }
+}
+
+pub async fn write_key(
+    Path((room_id, key)): Path<(String, String)>,
+    Query(query): Query<WriteKeyQuery>,
+    State(rooms): State<Arc<Rooms>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, RoomError> {
+    let action = Action::from_parts(
+        query.action.as_deref().unwrap_or("replace"),
+        query.seq.map(SequenceNumber),
+    )
+    .map_err(|e| RoomError::BadRequest(e.to_string()))?;
+
+    let value: Value = if header_contains(&headers, header::CONTENT_TYPE, CBOR_CONTENT_TYPE) {
+        ciborium::de::from_reader(body.as_ref())
+            .map_err(|e| RoomError::BadRequest(e.to_string()))?
+    } else {
+        serde_json::from_slice(&body).map_err(|e| RoomError::BadRequest(e.to_string()))?
+    };
+
+    let room = rooms.get(&room_id)?;
+    room.bump();
+
+    let conn = room.database.connect(|_| {});
+    conn.send_message(&MessageToDatabase::Push {
+        key: Key::new(key),
+        value,
+        action,
+        request_id: None,
+    })
+    .map_err(RoomError::Database)?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -1,8 +1,11 @@
 #![doc = include_str!("../README.md")]
 
-use crate::server::run_server;
+use crate::{
+    config::{Config, CONFIG_PATH_ENV},
+    server::run_server,
+};
 use clap::Parser;
-use std::net::IpAddr;
+use std::{net::IpAddr, path::PathBuf};
 use tracing_subscriber::{
     filter::{EnvFilter, LevelFilter},
     fmt,
@@ -10,15 +13,63 @@ use tracing_subscriber::{
     util::SubscriberInitExt,
 };
 
+mod changes;
+mod cluster;
+mod config;
+mod deflate;
+mod events;
+mod keys;
+mod replication;
+mod room;
 mod server;
+mod shutdown;
+mod storage;
+mod webhooks;
 
 #[derive(Parser)]
 pub struct Opts {
-    #[clap(long, default_value = "8080")]
-    port: u16,
+    /// Path to a TOML configuration file. Values in the file can be
+    /// overridden by `DRIFTDB_*` environment variables, which can in turn be
+    /// overridden by the flags below.
+    #[clap(long, env = CONFIG_PATH_ENV)]
+    config: Option<PathBuf>,
+
+    /// Port to listen on (default 8080).
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// Address to listen on (default 127.0.0.1).
+    #[clap(long)]
+    host: Option<IpAddr>,
+
+    /// Number of seconds a room is retained after its last activity
+    /// once no clients are connected to it (default one day).
+    #[clap(long)]
+    retention_seconds: Option<u64>,
+
+    /// Create rooms that do not exist when they are first accessed, instead
+    /// of returning a 404 error.
+    #[clap(long)]
+    create_rooms_on_access: bool,
 
-    #[clap(long, default_value = "127.0.0.1")]
-    host: IpAddr,
+    /// Maximum number of seconds to wait for clients to disconnect when
+    /// shutting down (default 10).
+    #[clap(long)]
+    shutdown_timeout_seconds: Option<u64>,
+
+    /// Number of seconds clients are told to wait before reconnecting when
+    /// the server shuts down (default 5).
+    #[clap(long)]
+    shutdown_retry_seconds: Option<u64>,
+
+    /// Path to a PEM-encoded TLS certificate chain. When given along with
+    /// `--tls-key`, the server serves HTTPS and WSS instead of HTTP and WS.
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM-encoded private key for `--tls-cert`.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -35,6 +86,16 @@ async fn main() {
         .with(filter)
         .init();
 
-    let result = run_server(&opts).await;
-    tracing::error!(?result, "Server exited.");
+    let config = match Config::load(&opts) {
+        Ok(config) => config,
+        Err(error) => {
+            tracing::error!("Invalid configuration: {:#}", error);
+            std::process::exit(1);
+        }
+    };
+
+    match run_server(config).await {
+        Ok(()) => tracing::info!("Server shut down."),
+        Err(error) => tracing::error!(?error, "Server exited."),
+    }
 }
@@ -0,0 +1,582 @@
+//! Leader–follower replication of rooms. A follower streams a room's change
+//! feed from the leader and applies each change to its own copy, which stays
+//! read-only until the follower is promoted. When the feed is lost, the
+//! follower resumes from the last change it applied, if the leader still
+//! retains the changes since then.
+
+use crate::{
+    config::{Config, ReplicationConfig},
+    room::{ConnectionGuard, Room, RoomError, Rooms},
+    server::check_api_key,
+    shutdown::ShutdownSignal,
+};
+use anyhow::{anyhow, bail, Result};
+use axum::{
+    body::StreamBody,
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use driftdb::{
+    types::{SequenceNumber, SequenceValue},
+    ApplyResult, Key, Store,
+};
+use hyper::{
+    body::{Bytes, HttpBody},
+    client::HttpConnector,
+    header::{self, HeaderMap},
+    Body, Client, Request, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Content type of the change feed: a sequence of CBOR byte strings, each
+/// holding the CBOR encoding of a [FeedEvent].
+const FEED_CONTENT_TYPE: &str = "application/cbor-seq";
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// An entry in a room's change feed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FeedEvent {
+    /// The full state of the room. The first event of a feed, unless the
+    /// follower resumed from a change which the leader still retains.
+    Snapshot {
+        seq: SequenceNumber,
+        data: HashMap<Key, Vec<SequenceValue>>,
+    },
+
+    /// A change applied to the room after the snapshot, or after the
+    /// change the follower resumed from.
+    Apply { result: ApplyResult },
+}
+
+impl FeedEvent {
+    /// Encode the event, wrapped in a byte string so that the follower can
+    /// tell from its first few bytes how long it is.
+    fn encode(&self) -> Bytes {
+        let mut event = Vec::new();
+        ciborium::ser::into_writer(self, &mut event).expect("Feed events should encode as CBOR.");
+
+        let mut buffer = Vec::with_capacity(event.len() + 9);
+        ciborium::ser::into_writer(&ciborium::value::Value::Bytes(event), &mut buffer)
+            .expect("Byte strings should encode as CBOR.");
+        buffer.into()
+    }
+}
+
+/// Read the header of a CBOR byte string, returning the length of the header
+/// and of the string, or `None` if the header has not fully arrived.
+fn byte_string_header(buffer: &[u8]) -> Result<Option<(usize, usize)>> {
+    let Some(&initial) = buffer.first() else {
+        return Ok(None);
+    };
+    if initial >> 5 != 2 {
+        bail!("Invalid replication feed: expected a byte string.");
+    }
+
+    let extra = match initial & 0x1f {
+        len @ 0..=23 => return Ok(Some((1, len as usize))),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => bail!("Invalid replication feed: unsupported byte string length."),
+    };
+    let Some(bytes) = buffer.get(1..1 + extra) else {
+        return Ok(None);
+    };
+    let len = bytes
+        .iter()
+        .fold(0u64, |len, byte| (len << 8) | u64::from(*byte));
+
+    Ok(Some((1 + extra, usize::try_from(len)?)))
+}
+
+/// Splits a replication feed into events as it arrives, decoding each event
+/// once all of it has arrived.
+#[derive(Default)]
+struct FeedDecoder {
+    buffer: Vec<u8>,
+
+    /// Offset in `buffer` of the first byte which has not been decoded.
+    position: usize,
+}
+
+impl FeedDecoder {
+    fn push(&mut self, chunk: &[u8]) {
+        // Drop decoded bytes once they make up most of the buffer, so that
+        // bytes are only moved a bounded number of times.
+        if self.position > self.buffer.len() / 2 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Decode the next event, if all of it has arrived.
+    fn next_event(&mut self) -> Result<Option<FeedEvent>> {
+        let remaining = &self.buffer[self.position..];
+        let Some((header, len)) = byte_string_header(remaining)? else {
+            return Ok(None);
+        };
+        let Some(event) = header
+            .checked_add(len)
+            .and_then(|end| remaining.get(header..end))
+        else {
+            return Ok(None);
+        };
+
+        let event = ciborium::de::from_reader(event)
+            .map_err(|err| anyhow!("Invalid replication feed: {:?}", err))?;
+        self.position += header + len;
+
+        Ok(Some(event))
+    }
+}
+
+/// The events to start a feed with, and a receiver of the changes which
+/// follow them. If the room still retains every change after `seq`, the feed
+/// resumes with those; otherwise it starts with a snapshot.
+fn start_feed(
+    room: &Room,
+    seq: Option<SequenceNumber>,
+) -> (Vec<FeedEvent>, broadcast::Receiver<ApplyResult>) {
+    if let Some((backlog, changes)) = seq.and_then(|seq| room.changes_since(seq)) {
+        let events = backlog
+            .into_iter()
+            .map(|result| FeedEvent::Apply { result })
+            .collect();
+        return (events, changes);
+    }
+
+    let (store, changes) = room.subscribe_changes();
+    let snapshot = FeedEvent::Snapshot {
+        seq: store.sequence_number(),
+        data: store.dump(),
+    };
+
+    (vec![snapshot], changes)
+}
+
+async fn stream_changes(
+    _guard: ConnectionGuard,
+    start: Vec<FeedEvent>,
+    mut changes: broadcast::Receiver<ApplyResult>,
+    sender: mpsc::Sender<Result<Bytes, Infallible>>,
+    mut shutdown: ShutdownSignal,
+    mut closed: ShutdownSignal,
+) {
+    for event in start {
+        if sender.send(Ok(event.encode())).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        tokio::select! {
+            result = changes.recv() => {
+                let result = match result {
+                    Ok(result) => result,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // The follower can't be brought up to date from here,
+                        // so end the feed and let it resume from the change log
+                        // or start over from a snapshot.
+                        tracing::warn!(?skipped, "Replication feed fell behind.");
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if sender.send(Ok(FeedEvent::Apply { result }.encode())).await.is_err() {
+                    break;
+                }
+            }
+            _ = sender.closed() => {
+                // The follower has gone away.
+                break;
+            }
+            _ = shutdown.wait() => break,
+            _ = closed.wait() => break,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReplicateQuery {
+    /// The last change the follower applied, if it has a copy of the room
+    /// from an earlier feed.
+    seq: Option<u64>,
+}
+
+/// Stream every change to a room to a follower, starting with a snapshot of
+/// its current state or with the changes after the follower's copy.
+pub async fn replicate(
+    Path(room_id): Path<String>,
+    Query(query): Query<ReplicateQuery>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    State(shutdown): State<ShutdownSignal>,
+    headers: HeaderMap,
+) -> std::result::Result<Response, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    // Followers don't count towards the room's connection limit.
+    let (room, guard) = rooms.connect_unlimited(&room_id)?;
+
+    let (start, changes) = start_feed(&room, query.seq.map(SequenceNumber));
+    let (sender, receiver) = mpsc::channel(32);
+    tokio::spawn(stream_changes(
+        guard,
+        start,
+        changes,
+        sender,
+        shutdown,
+        room.closed(),
+    ));
+
+    Ok((
+        [(header::CONTENT_TYPE, FEED_CONTENT_TYPE)],
+        StreamBody::new(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+/// A room which this server replicates from a leader.
+struct Follower {
+    task: JoinHandle<()>,
+
+    /// Set while the feed from the leader is open.
+    connected: Arc<AtomicBool>,
+}
+
+/// The rooms which this server follows.
+#[derive(Default)]
+pub struct Followers {
+    followers: DashMap<String, Follower>,
+    leader_url: Option<String>,
+}
+
+impl Followers {
+    /// Begin replicating every room in `config` from the leader. The rooms
+    /// are read-only until they are promoted.
+    pub fn start(
+        config: &ReplicationConfig,
+        rooms: &Rooms,
+        shutdown: ShutdownSignal,
+    ) -> Result<Self> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client: HttpsClient = Client::builder().build(connector);
+        let leader_url = config.leader_url.trim_end_matches('/').to_string();
+        let reconnect = Duration::from_secs(config.reconnect_seconds);
+
+        let followers = DashMap::new();
+        for room_id in &config.rooms {
+            let (room, guard) = rooms
+                .get_or_create(room_id)
+                .and_then(|_| rooms.connect_unlimited(room_id))
+                .map_err(|err| anyhow!("Could not create room {:?}: {:?}", room_id, err))?;
+            room.database.set_read_only(true);
+
+            let url: Uri = format!("{}/room/{}/replicate", leader_url, room_id).parse()?;
+            let connected = Arc::new(AtomicBool::new(false));
+            let task = tokio::spawn(follow(
+                client.clone(),
+                url,
+                config.api_key.clone(),
+                guard,
+                connected.clone(),
+                reconnect,
+                shutdown.clone(),
+            ));
+
+            followers.insert(room_id.clone(), Follower { task, connected });
+        }
+
+        Ok(Self {
+            followers,
+            leader_url: Some(leader_url),
+        })
+    }
+
+    /// Stop replicating a room, and allow clients to write to it.
+    pub fn promote(&self, room_id: &str, rooms: &Rooms) -> std::result::Result<(), RoomError> {
+        let (_, follower) = self.followers.remove(room_id).ok_or(RoomError::NotFound)?;
+        follower.task.abort();
+
+        rooms.get(room_id)?.database.set_read_only(false);
+        tracing::info!(?room_id, "Promoted room to leader.");
+
+        Ok(())
+    }
+}
+
+/// Replicate a room from the leader until the server shuts down, reconnecting
+/// whenever the feed is lost.
+async fn follow(
+    client: HttpsClient,
+    url: Uri,
+    api_key: Option<String>,
+    guard: ConnectionGuard,
+    connected: Arc<AtomicBool>,
+    reconnect: Duration,
+    mut shutdown: ShutdownSignal,
+) {
+    // The guard keeps the room from being reaped while it is followed.
+    let room = guard.room();
+
+    // Whether the room holds a copy of the leader's state from an earlier
+    // feed, which a new feed can resume from.
+    let mut synced = false;
+
+    loop {
+        tokio::select! {
+            result = follow_once(&client, &url, api_key.as_deref(), room, &connected, &mut synced) => {
+                match result {
+                    Ok(()) => tracing::info!(?url, "Leader closed replication feed."),
+                    Err(err) => tracing::warn!(?err, ?url, "Error replicating from leader."),
+                }
+            }
+            _ = shutdown.wait() => break,
+        }
+        connected.store(false, Ordering::SeqCst);
+
+        tokio::select! {
+            _ = tokio::time::sleep(reconnect) => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+}
+
+async fn follow_once(
+    client: &HttpsClient,
+    url: &Uri,
+    api_key: Option<&str>,
+    room: &Room,
+    connected: &AtomicBool,
+    synced: &mut bool,
+) -> Result<()> {
+    let url = if *synced {
+        let seq = room.database.with_store(Store::sequence_number);
+        format!("{}?seq={}", url, seq.0).parse()?
+    } else {
+        url.clone()
+    };
+
+    let mut request = Request::get(url);
+    if let Some(api_key) = api_key {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", api_key));
+    }
+
+    let response = client.request(request.body(Body::empty())?).await?;
+    if !response.status().is_success() {
+        bail!("Leader responded with {}.", response.status());
+    }
+    connected.store(true, Ordering::SeqCst);
+
+    let mut body = response.into_body();
+    let mut decoder = FeedDecoder::default();
+    while let Some(chunk) = body.data().await {
+        decoder.push(&chunk?);
+
+        while let Some(event) = decoder.next_event()? {
+            apply_event(room, event);
+            *synced = true;
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_event(room: &Room, event: FeedEvent) {
+    match event {
+        FeedEvent::Snapshot { seq, data } => room.reset(Store::from_dump(data, seq)),
+        FeedEvent::Apply { result } => room.database.apply_replicated(&result),
+    }
+}
+
+/// Stop following the leader, and accept writes to the room.
+pub async fn promote(
+    Path(room_id): Path<String>,
+    State(rooms): State<Arc<Rooms>>,
+    State(followers): State<Arc<Followers>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> std::result::Result<StatusCode, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+    followers.promote(&room_id, &rooms)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Role {
+    Leader,
+    Follower,
+}
+
+#[derive(Serialize)]
+pub struct ReplicationStatus {
+    role: Role,
+
+    /// The room's sequence number, i.e. the last change replicated to it
+    /// when the room is a follower.
+    seq: SequenceNumber,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leader_url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connected: Option<bool>,
+}
+
+/// Report whether a room is followed, and how far it has been replicated.
+pub async fn replication_status(
+    Path(room_id): Path<String>,
+    State(rooms): State<Arc<Rooms>>,
+    State(followers): State<Arc<Followers>>,
+) -> std::result::Result<Json<ReplicationStatus>, RoomError> {
+    let room = rooms.get(&room_id)?;
+    let seq = room.database.with_store(Store::sequence_number);
+
+    let status = match followers.followers.get(&room_id) {
+        Some(follower) => ReplicationStatus {
+            role: Role::Follower,
+            seq,
+            leader_url: followers.leader_url.clone(),
+            connected: Some(follower.connected.load(Ordering::SeqCst)),
+        },
+        None => ReplicationStatus {
+            role: Role::Leader,
+            seq,
+            leader_url: None,
+            connected: None,
+        },
+    };
+
+    Ok(Json(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::RoomsConfig, storage::Storage};
+    use driftdb::{types::Action, MessageToDatabase};
+
+    #[test]
+    fn test_apply_feed() {
+        let rooms = Rooms::new(RoomsConfig::default(), Storage::Memory);
+        let leader = rooms.get_or_create("leader").unwrap();
+        let follower = rooms.get_or_create("follower").unwrap();
+
+        let conn = leader.database.connect(|_| {});
+        let push = |value: i32| {
+            conn.send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: ciborium::Value::Integer(value.into()),
+                action: Action::Append,
+                request_id: None,
+            })
+            .unwrap();
+        };
+
+        push(1);
+        let (store, mut changes) = leader.subscribe_changes();
+        push(2);
+
+        // Encode the feed as one stream, as a follower would receive it.
+        let mut feed = FeedEvent::Snapshot {
+            seq: store.sequence_number(),
+            data: store.dump(),
+        }
+        .encode()
+        .to_vec();
+        feed.extend_from_slice(
+            &FeedEvent::Apply {
+                result: changes.try_recv().unwrap(),
+            }
+            .encode(),
+        );
+
+        // Deliver the feed a byte at a time, so events span many chunks.
+        let mut decoder = FeedDecoder::default();
+        let mut events = 0;
+        for byte in feed {
+            decoder.push(&[byte]);
+            while let Some(event) = decoder.next_event().unwrap() {
+                apply_event(&follower, event);
+                events += 1;
+            }
+        }
+        assert_eq!(2, events);
+
+        assert_eq!(
+            leader.database.snapshot().dump(),
+            follower.database.snapshot().dump()
+        );
+        assert_eq!(
+            SequenceNumber(2),
+            follower.database.snapshot().sequence_number()
+        );
+    }
+
+    #[test]
+    fn test_resume_feed() {
+        let rooms = Rooms::new(RoomsConfig::default(), Storage::Memory);
+        let leader = rooms.get_or_create("leader").unwrap();
+
+        let conn = leader.database.connect(|_| {});
+        for value in 0..3 {
+            conn.send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: ciborium::Value::Integer(value.into()),
+                action: Action::Append,
+                request_id: None,
+            })
+            .unwrap();
+        }
+
+        // A follower without a copy starts from a snapshot.
+        let (events, _) = start_feed(&leader, None);
+        assert!(matches!(
+            events.as_slice(),
+            [FeedEvent::Snapshot {
+                seq: SequenceNumber(3),
+                ..
+            }]
+        ));
+
+        // A follower with a copy resumes from the changes after it.
+        let (events, _) = start_feed(&leader, Some(SequenceNumber(1)));
+        assert_eq!(2, events.len());
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, FeedEvent::Apply { .. })));
+
+        // A follower ahead of the leader starts over from a snapshot.
+        let (events, _) = start_feed(&leader, Some(SequenceNumber(5)));
+        assert!(matches!(events.as_slice(), [FeedEvent::Snapshot { .. }]));
+    }
+}
@@ -0,0 +1,1032 @@
+use crate::{
+    cluster::{Cluster, Node},
+    config::RoomsConfig,
+    shutdown::{Shutdown, ShutdownSignal},
+    storage::Storage,
+    webhooks::{RoomWebhooks, WebhookSender},
+};
+use axum::response::{IntoResponse, Redirect, Response};
+use dashmap::{mapref::entry::Entry, DashMap};
+use driftdb::{
+    types::{validate_room_id, SequenceNumber},
+    ApplyResult, Database, PushInstruction, Store,
+};
+use hyper::StatusCode;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Bounds on how often the reaper checks for idle rooms.
+const MIN_REAP_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of recent changes retained by each room, and buffered for each
+/// change feed. A feed which falls further behind is disconnected.
+const CHANGES_CAPACITY: usize = 1024;
+
+/// How often to check whether clients have disconnected during shutdown.
+const DISCONNECT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A room held in memory by the server, along with the bookkeeping needed
+/// to decide when it can be dropped.
+pub struct Room {
+    pub database: Arc<Database>,
+    pub webhooks: Arc<RoomWebhooks>,
+    last_active: Mutex<Instant>,
+    connections: AtomicUsize,
+
+    /// Disconnected sessions which may still be resumed. These keep the room
+    /// from being reaped, but don't count as connections.
+    detached_sessions: AtomicUsize,
+
+    /// Set when the room has changed since it was last persisted.
+    dirty: Arc<AtomicBool>,
+
+    /// Signalled when the room is dropped by this server while clients may
+    /// still be connected, e.g. when it is handed off to another node.
+    closed: Shutdown,
+
+    /// Every change applied to the room, for replication and change feeds.
+    changes: broadcast::Sender<ApplyResult>,
+
+    /// The most recent changes, so that change feeds can resume.
+    change_log: Arc<Mutex<ChangeLog>>,
+}
+
+/// Recent changes to a room, oldest first.
+struct ChangeLog {
+    changes: VecDeque<ApplyResult>,
+
+    /// Changes up to and including this sequence number are not retained.
+    truncated_through: SequenceNumber,
+}
+
+impl ChangeLog {
+    fn new(truncated_through: SequenceNumber) -> Self {
+        Self {
+            changes: VecDeque::new(),
+            truncated_through,
+        }
+    }
+
+    fn push(&mut self, result: &ApplyResult) {
+        self.changes.push_back(result.clone());
+
+        while self.changes.len() > CHANGES_CAPACITY {
+            let Some(oldest) = self.changes.pop_front() else {
+                break;
+            };
+            self.truncated_through = oldest.seq;
+        }
+    }
+}
+
+impl Room {
+    pub fn new(webhooks: RoomWebhooks) -> Self {
+        Self::from_store(Store::default(), webhooks)
+    }
+
+    pub fn from_store(store: Store, webhooks: RoomWebhooks) -> Self {
+        let dirty = Arc::new(AtomicBool::new(false));
+        let webhooks = Arc::new(webhooks);
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+        let change_log = Arc::new(Mutex::new(ChangeLog::new(store.sequence_number())));
+
+        let mut database = Database::new_from_store(store);
+        {
+            let dirty = dirty.clone();
+            let webhooks = webhooks.clone();
+            let changes = changes.clone();
+            let change_log = change_log.clone();
+            database.set_replica_callback(move |result: &ApplyResult| {
+                dirty.store(true, Ordering::SeqCst);
+                webhooks.notify(result);
+                change_log.lock().unwrap().push(result);
+
+                if changes.receiver_count() > 0 {
+                    // This only fails if every receiver has just gone away.
+                    let _ = changes.send(result.clone());
+                }
+            });
+        }
+
+        Self {
+            database: Arc::new(database),
+            webhooks,
+            last_active: Mutex::new(Instant::now()),
+            connections: AtomicUsize::new(0),
+            detached_sessions: AtomicUsize::new(0),
+            dirty,
+            closed: Shutdown::new(Duration::ZERO),
+            changes,
+            change_log,
+        }
+    }
+
+    /// Return the current state of the room, along with a receiver of every
+    /// change made after that state.
+    pub fn subscribe_changes(&self) -> (Store, broadcast::Receiver<ApplyResult>) {
+        // The callback which feeds the channel runs with the database locked,
+        // so no change can fall between the snapshot and the subscription.
+        self.database
+            .with_store(|store| (store.clone(), self.changes.subscribe()))
+    }
+
+    /// Return the changes made to the room after `seq`, along with a receiver
+    /// of every change made after those. Returns `None` if some of the
+    /// changes after `seq` are no longer retained.
+    pub fn changes_since(
+        &self,
+        seq: SequenceNumber,
+    ) -> Option<(Vec<ApplyResult>, broadcast::Receiver<ApplyResult>)> {
+        self.database.with_store(|store| {
+            let log = self.change_log.lock().unwrap();
+            if seq < log.truncated_through || seq > store.sequence_number() {
+                return None;
+            }
+
+            let changes = log
+                .changes
+                .iter()
+                .filter(|result| {
+                    // A compaction shares the sequence number of the change
+                    // before it, so resend it; applying one twice is harmless.
+                    result.seq > seq
+                        || (result.seq == seq
+                            && matches!(
+                                result.push_instruction,
+                                Some(PushInstruction::PushStart(_))
+                            ))
+                })
+                .cloned()
+                .collect();
+
+            Some((changes, self.changes.subscribe()))
+        })
+    }
+
+    /// Replace the contents of the room.
+    pub fn reset(&self, store: Store) {
+        *self.change_log.lock().unwrap() = ChangeLog::new(store.sequence_number());
+        self.database.reset(store);
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// A signal which fires when connections to the room should be closed.
+    pub fn closed(&self) -> ShutdownSignal {
+        self.closed.signal()
+    }
+
+    /// Reset the retention deadline of the room. This mirrors the worker,
+    /// which bumps its cleanup alarm whenever a message is received.
+    pub fn bump(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    /// Register a live connection to the room. The room will not be reaped
+    /// while the returned guard is alive.
+    pub fn connect(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        self.bump();
+
+        ConnectionGuard { room: self.clone() }
+    }
+
+    /// Like [Room::connect], but fails if the room already has `limit`
+    /// connections.
+    pub fn try_connect(self: &Arc<Self>, limit: usize) -> Option<ConnectionGuard> {
+        self.connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .ok()?;
+        self.bump();
+
+        Some(ConnectionGuard { room: self.clone() })
+    }
+
+    /// Keep the room from being reaped while a disconnected session may
+    /// still be resumed, until the returned guard is dropped.
+    pub fn hold_session(self: &Arc<Self>) -> SessionGuard {
+        self.detached_sessions.fetch_add(1, Ordering::SeqCst);
+
+        SessionGuard { room: self.clone() }
+    }
+
+    /// True if the room has no connections or resumable sessions, and has not
+    /// been active for at least `retention`.
+    pub fn is_idle(&self, retention: Duration) -> bool {
+        self.connections.load(Ordering::SeqCst) == 0
+            && self.detached_sessions.load(Ordering::SeqCst) == 0
+            && self.last_active.lock().unwrap().elapsed() >= retention
+    }
+
+    /// Clear the dirty flag, returning whether it was set.
+    fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::SeqCst)
+    }
+}
+
+pub struct ConnectionGuard {
+    room: Arc<Room>,
+}
+
+impl ConnectionGuard {
+    /// The room the connection is registered with.
+    pub fn room(&self) -> &Arc<Room> {
+        &self.room
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        // Start the retention clock from when the last client left.
+        self.room.bump();
+        self.room.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A session which may still be resumed. Returned by [Room::hold_session].
+pub struct SessionGuard {
+    room: Arc<Room>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.room.bump();
+        self.room.detached_sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Errors arising from looking up or creating a room.
+#[derive(Debug)]
+pub enum RoomError {
+    /// The room does not exist, and the server is not configured to create it.
+    NotFound,
+
+    /// The room ID is not acceptable as a room name.
+    InvalidRoomId(driftdb::Error),
+
+    /// The request to create a room could not be understood.
+    BadRequest(String),
+
+    /// The request lacked a valid API key.
+    Unauthorized,
+
+    /// The request is not allowed with the server's configuration.
+    Forbidden(String),
+
+    /// The server already holds the maximum number of rooms.
+    TooManyRooms,
+
+    /// The room already has the maximum number of connections.
+    TooManyConnections,
+
+    /// The room is owned by another node of the cluster, at the given URL.
+    Moved(String),
+
+    /// The previous owner of the room has not yet handed it off.
+    HandingOff,
+
+    /// A room with the requested ID already exists.
+    AlreadyExists,
+
+    /// The room could not be loaded from storage.
+    Storage(anyhow::Error),
+
+    /// The database rejected a message, e.g. a push to a read-only replica.
+    Database(driftdb::Error),
+}
+
+impl IntoResponse for RoomError {
+    fn into_response(self) -> Response {
+        match self {
+            RoomError::NotFound => (StatusCode::NOT_FOUND, "Room not found.").into_response(),
+            RoomError::InvalidRoomId(err) => {
+                (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+            }
+            RoomError::BadRequest(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+            RoomError::Unauthorized => {
+                (StatusCode::UNAUTHORIZED, "Missing or invalid API key.").into_response()
+            }
+            RoomError::Forbidden(message) => (StatusCode::FORBIDDEN, message).into_response(),
+            RoomError::TooManyRooms => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The server has reached its room limit.",
+            )
+                .into_response(),
+            RoomError::TooManyConnections => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The room has reached its connection limit.",
+            )
+                .into_response(),
+            RoomError::Moved(url) => Redirect::temporary(&url).into_response(),
+            RoomError::HandingOff => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The room is being handed off to this node; try again shortly.",
+            )
+                .into_response(),
+            RoomError::AlreadyExists => {
+                (StatusCode::CONFLICT, "Room already exists.").into_response()
+            }
+            RoomError::Database(err) => {
+                let status = StatusCode::from_u16(err.code().http_status())
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                (status, err.to_string()).into_response()
+            }
+            RoomError::Storage(err) => {
+                tracing::error!(?err, "Error loading room from storage.");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Error loading room.").into_response()
+            }
+        }
+    }
+}
+
+/// The set of rooms held by the server. All room lookups and creation from
+/// request handlers go through this, so that policy (validation, lazy
+/// creation, limits, persistence and expiry) is applied in one place.
+pub struct Rooms {
+    rooms: DashMap<String, Arc<Room>>,
+    config: RoomsConfig,
+    storage: Storage,
+    webhooks: Option<WebhookSender>,
+    cluster: Option<Arc<Cluster>>,
+
+    /// Rooms dropped by this node which could not yet be persisted for their
+    /// new owner. Their leases are kept until they are.
+    handing_off: DashMap<String, Arc<Room>>,
+}
+
+impl Rooms {
+    pub fn new(config: RoomsConfig, storage: Storage) -> Self {
+        Self {
+            rooms: DashMap::new(),
+            config,
+            storage,
+            webhooks: None,
+            cluster: None,
+            handing_off: DashMap::new(),
+        }
+    }
+
+    /// Only create rooms owned by this node of `cluster`.
+    pub fn with_cluster(mut self, cluster: Arc<Cluster>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// If the room is owned by another node of the cluster, return that node.
+    pub fn remote_owner(&self, room_id: &str) -> Option<Node> {
+        self.cluster.as_ref()?.remote_owner(room_id)
+    }
+
+    /// Deliver changes to webhooks registered on rooms through `sender`.
+    pub fn with_webhooks(mut self, sender: WebhookSender) -> Self {
+        self.webhooks = Some(sender);
+        self
+    }
+
+    /// In a cluster, take the lease on a room before loading or creating it,
+    /// so that it is not served while its previous owner may still change it.
+    fn acquire_lease(&self, room_id: &str) -> Result<(), RoomError> {
+        let Some(cluster) = &self.cluster else {
+            return Ok(());
+        };
+
+        if self.handing_off.contains_key(room_id) {
+            return Err(RoomError::HandingOff);
+        }
+
+        match cluster.acquire_lease(room_id) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(RoomError::HandingOff),
+            Err(err) => Err(RoomError::Storage(err)),
+        }
+    }
+
+    fn release_lease(&self, room_id: &str) {
+        if let Some(cluster) = &self.cluster {
+            if let Err(err) = cluster.release_lease(room_id) {
+                tracing::error!(?err, ?room_id, "Error releasing room lease.");
+            }
+        }
+    }
+
+    fn new_room(&self, room_id: &str, store: Store) -> Room {
+        let room = Room::from_store(store, RoomWebhooks::new(room_id, self.webhooks.clone()));
+        if let Some(history) = &self.config.history {
+            room.database.enable_history(history.into());
+        }
+
+        room
+    }
+
+    /// Look up an existing room, loading it from storage if necessary. If the
+    /// room does not exist, it is created when the server allows rooms to be
+    /// created on access.
+    pub fn get(&self, room_id: &str) -> Result<Arc<Room>, RoomError> {
+        self.load_or_create(room_id, self.config.create_on_access)
+    }
+
+    /// Return the room with the given ID, creating it if it does not exist.
+    pub fn get_or_create(&self, room_id: &str) -> Result<Arc<Room>, RoomError> {
+        let room = self.load_or_create(room_id, true)?;
+        room.bump();
+
+        Ok(room)
+    }
+
+    /// Create a new room with a random ID.
+    pub fn create_random(&self) -> Result<(String, Arc<Room>), RoomError> {
+        self.create_random_from(Store::default())
+    }
+
+    /// Create a new room with a random ID, holding the contents of `store`.
+    fn create_random_from(&self, store: Store) -> Result<(String, Arc<Room>), RoomError> {
+        self.check_room_limit()?;
+
+        // In a cluster, pick an ID which this node owns.
+        let room_id = loop {
+            let room_id = Uuid::new_v4().to_string();
+            if self.remote_owner(&room_id).is_none() {
+                break room_id;
+            }
+        };
+        self.acquire_lease(&room_id)?;
+        let room = Arc::new(self.new_room(&room_id, store));
+        self.rooms.insert(room_id.clone(), room.clone());
+
+        Ok((room_id, room))
+    }
+
+    /// Create a copy of `source`, with the given ID or a random one. The
+    /// copy keeps the keys, values and sequence numbers of the source.
+    pub fn fork(
+        &self,
+        source: &Room,
+        room_id: Option<&str>,
+    ) -> Result<(String, Arc<Room>), RoomError> {
+        let snapshot = source.database.snapshot();
+        let store = Store::from_dump(snapshot.dump(), snapshot.sequence_number());
+
+        let (room_id, room) = match room_id {
+            Some(room_id) => (room_id.to_string(), self.create(room_id, store)?),
+            None => self.create_random_from(store)?,
+        };
+
+        // Persist the copy even if nobody writes to it.
+        room.dirty.store(true, Ordering::SeqCst);
+
+        Ok((room_id, room))
+    }
+
+    /// Create a room with the given ID holding the contents of `store`, e.g.
+    /// one exported from another deployment. Fails if the room exists.
+    pub fn import(&self, room_id: &str, store: Store) -> Result<Arc<Room>, RoomError> {
+        let room = self.create(room_id, store)?;
+        room.dirty.store(true, Ordering::SeqCst);
+
+        Ok(room)
+    }
+
+    /// Create a room with the given ID and contents, failing if it exists.
+    fn create(&self, room_id: &str, store: Store) -> Result<Arc<Room>, RoomError> {
+        validate_room_id(room_id).map_err(RoomError::InvalidRoomId)?;
+
+        if self.remote_owner(room_id).is_some() {
+            return Err(RoomError::BadRequest(
+                "The room would be owned by another node of the cluster.".to_string(),
+            ));
+        }
+
+        if self.rooms.contains_key(room_id) {
+            return Err(RoomError::AlreadyExists);
+        }
+
+        self.acquire_lease(room_id)?;
+        if self
+            .storage
+            .load(room_id)
+            .map_err(RoomError::Storage)?
+            .is_some()
+        {
+            return Err(RoomError::AlreadyExists);
+        }
+
+        self.check_room_limit()?;
+
+        match self.rooms.entry(room_id.to_string()) {
+            Entry::Occupied(_) => Err(RoomError::AlreadyExists),
+            Entry::Vacant(entry) => {
+                tracing::info!(?room_id, "Creating room.");
+                Ok(entry
+                    .insert(Arc::new(self.new_room(room_id, store)))
+                    .clone())
+            }
+        }
+    }
+
+    /// Look up a room and register a connection to it, subject to the
+    /// per-room connection limit.
+    pub fn connect(&self, room_id: &str) -> Result<(Arc<Room>, ConnectionGuard), RoomError> {
+        self.connect_with_limit(room_id, self.config.max_connections_per_room)
+    }
+
+    /// Like [Rooms::connect], but not subject to the connection limit, e.g.
+    /// for followers replicating the room.
+    pub fn connect_unlimited(
+        &self,
+        room_id: &str,
+    ) -> Result<(Arc<Room>, ConnectionGuard), RoomError> {
+        self.connect_with_limit(room_id, None)
+    }
+
+    fn connect_with_limit(
+        &self,
+        room_id: &str,
+        limit: Option<usize>,
+    ) -> Result<(Arc<Room>, ConnectionGuard), RoomError> {
+        loop {
+            self.get(room_id)?;
+
+            // Register the connection while holding the map entry, which
+            // keeps the reaper from removing the room in the meantime. If it
+            // was removed since the lookup, look it up again.
+            let Some(room) = self.rooms.get(room_id) else {
+                continue;
+            };
+
+            let guard = match limit {
+                Some(limit) => room
+                    .try_connect(limit)
+                    .ok_or(RoomError::TooManyConnections)?,
+                None => room.connect(),
+            };
+
+            return Ok((room.clone(), guard));
+        }
+    }
+
+    fn check_room_limit(&self) -> Result<(), RoomError> {
+        match self.config.max_rooms {
+            Some(max_rooms) if self.rooms.len() >= max_rooms => Err(RoomError::TooManyRooms),
+            _ => Ok(()),
+        }
+    }
+
+    fn load_or_create(&self, room_id: &str, create: bool) -> Result<Arc<Room>, RoomError> {
+        if let Some(room) = self.rooms.get(room_id) {
+            return Ok(room.clone());
+        }
+
+        // Validate before touching storage, since the room ID is used as a file name.
+        if let Err(err) = validate_room_id(room_id) {
+            return Err(if create {
+                RoomError::InvalidRoomId(err)
+            } else {
+                RoomError::NotFound
+            });
+        }
+
+        self.acquire_lease(room_id)?;
+        let store = self.storage.load(room_id).map_err(RoomError::Storage)?;
+        if store.is_none() && !create {
+            return Err(RoomError::NotFound);
+        }
+
+        self.check_room_limit()?;
+
+        match self.rooms.entry(room_id.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let room = match store {
+                    Some(store) => {
+                        tracing::info!(?room_id, "Loaded room from storage.");
+                        self.new_room(room_id, store)
+                    }
+                    None => {
+                        tracing::info!(?room_id, "Creating room.");
+                        self.new_room(room_id, Store::default())
+                    }
+                };
+
+                Ok(entry.insert(Arc::new(room)).clone())
+            }
+        }
+    }
+
+    /// Number of clients currently connected across all rooms.
+    pub fn connection_count(&self) -> usize {
+        self.rooms
+            .iter()
+            .map(|room| room.connections.load(Ordering::SeqCst))
+            .sum()
+    }
+
+    /// Wait until no clients are connected to any room.
+    pub async fn wait_for_disconnect(&self) {
+        while self.connection_count() > 0 {
+            tokio::time::sleep(DISCONNECT_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Remove rooms which have been idle for longer than the retention period,
+    /// both from memory and from storage.
+    pub fn remove_idle(&self) {
+        let retention = self.config.retention();
+
+        self.rooms.retain(|room_id, room| {
+            if room.is_idle(retention) {
+                tracing::info!(?room_id, "Removing idle room.");
+                if let Err(err) = self.storage.delete(room_id) {
+                    tracing::error!(?err, ?room_id, "Error deleting room from storage.");
+                }
+                self.release_lease(room_id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Periodically remove idle rooms. Runs forever.
+    pub async fn reap_idle_rooms(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(
+            self.config
+                .retention()
+                .clamp(MIN_REAP_INTERVAL, MAX_REAP_INTERVAL),
+        );
+
+        loop {
+            interval.tick().await;
+            self.remove_idle();
+        }
+    }
+
+    /// Write every room which has changed since it was last persisted to storage.
+    pub fn flush(&self) {
+        if !self.storage.is_persistent() {
+            return;
+        }
+
+        for entry in self.rooms.iter() {
+            let (room_id, room) = entry.pair();
+            if !room.take_dirty() {
+                continue;
+            }
+
+            if let Err(err) = self.storage.save(room_id, &room.database.snapshot()) {
+                tracing::error!(?err, ?room_id, "Error persisting room.");
+                // Try again on the next flush.
+                room.dirty.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Release rooms which this node no longer owns: stop accepting writes,
+    /// disconnect their clients (who reconnect to the new owner), persist
+    /// them and release their leases, so that the new owner can load them.
+    pub fn hand_off(&self) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+
+        let moved: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|entry| cluster.remote_owner(entry.key()).is_some())
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        for room_id in moved {
+            let Some((room_id, room)) = self.rooms.remove(&room_id) else {
+                continue;
+            };
+
+            // Stop writes before taking the final snapshot, so that none
+            // are made after it.
+            room.database.set_read_only(true);
+            room.closed.begin();
+            self.handing_off.insert(room_id, room);
+        }
+
+        // Retry rooms which could not be persisted last time, too.
+        let pending: Vec<(String, Arc<Room>)> = self
+            .handing_off
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        for (room_id, room) in pending {
+            if room.take_dirty() {
+                if let Err(err) = self.storage.save(&room_id, &room.database.snapshot()) {
+                    tracing::error!(?err, ?room_id, "Error persisting room for hand-off.");
+                    // Keep the lease, and try again next time.
+                    room.dirty.store(true, Ordering::SeqCst);
+                    continue;
+                }
+            }
+
+            self.release_lease(&room_id);
+            self.handing_off.remove(&room_id);
+            tracing::info!(?room_id, "Handed off room.");
+        }
+    }
+
+    /// Periodically persist changed rooms. Runs forever.
+    pub async fn flush_periodically(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+            self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driftdb::{types::Action, MessageToDatabase};
+
+    fn rooms(config: RoomsConfig) -> Rooms {
+        Rooms::new(config, Storage::Memory)
+    }
+
+    #[test]
+    fn test_room_with_connection_is_not_idle() {
+        let room = Arc::new(Room::new(RoomWebhooks::new("test", None)));
+        assert!(room.is_idle(Duration::ZERO));
+
+        let guard = room.connect();
+        assert!(!room.is_idle(Duration::ZERO));
+
+        drop(guard);
+        assert!(room.is_idle(Duration::ZERO));
+        assert!(!room.is_idle(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_held_session_is_not_a_connection() {
+        let rooms = rooms(RoomsConfig {
+            max_connections_per_room: Some(1),
+            ..RoomsConfig::default()
+        });
+        rooms.get_or_create("room").unwrap();
+        let (room, guard) = rooms.connect("room").unwrap();
+        let session = room.hold_session();
+        drop(guard);
+
+        // The session keeps the room alive, but not its connection slot.
+        assert!(!room.is_idle(Duration::ZERO));
+        assert_eq!(0, rooms.connection_count());
+        assert!(rooms.connect("room").is_ok());
+
+        drop(session);
+        assert!(room.is_idle(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let room = Room::new(RoomWebhooks::new("test", None));
+        let conn = room.database.connect(|_| {});
+        for value in 0..CHANGES_CAPACITY + 2 {
+            conn.send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: ciborium::Value::Integer(value.into()),
+                action: Action::Append,
+                request_id: None,
+            })
+            .unwrap();
+        }
+
+        // The two oldest changes have been dropped.
+        assert!(room.changes_since(SequenceNumber(1)).is_none());
+
+        let (backlog, mut changes) = room.changes_since(SequenceNumber(2)).unwrap();
+        assert_eq!(CHANGES_CAPACITY, backlog.len());
+        assert_eq!(SequenceNumber(3), backlog[0].seq);
+
+        let (backlog, _) = room
+            .changes_since(SequenceNumber(CHANGES_CAPACITY as u64 + 2))
+            .unwrap();
+        assert!(backlog.is_empty());
+        assert!(room
+            .changes_since(SequenceNumber(CHANGES_CAPACITY as u64 + 3))
+            .is_none());
+
+        conn.send_message(&MessageToDatabase::Push {
+            key: "foo".into(),
+            value: ciborium::Value::Null,
+            action: Action::Compact {
+                seq: SequenceNumber(CHANGES_CAPACITY as u64 + 2),
+            },
+            request_id: None,
+        })
+        .unwrap();
+        assert_eq!(
+            SequenceNumber(CHANGES_CAPACITY as u64 + 2),
+            changes.try_recv().unwrap().seq
+        );
+
+        // Resuming after the change before the compaction includes it.
+        let (backlog, _) = room
+            .changes_since(SequenceNumber(CHANGES_CAPACITY as u64 + 2))
+            .unwrap();
+        assert_eq!(1, backlog.len());
+    }
+
+    #[test]
+    fn test_unknown_room_is_not_found() {
+        let rooms = rooms(RoomsConfig::default());
+        assert!(matches!(rooms.get("missing"), Err(RoomError::NotFound)));
+
+        rooms.get_or_create("missing").unwrap();
+        assert!(rooms.get("missing").is_ok());
+    }
+
+    #[test]
+    fn test_create_on_access() {
+        let rooms = rooms(RoomsConfig {
+            create_on_access: true,
+            ..RoomsConfig::default()
+        });
+        assert!(rooms.get("lazy-room").is_ok());
+        assert!(matches!(
+            rooms.get("not a room"),
+            Err(RoomError::InvalidRoomId(_))
+        ));
+    }
+
+    #[test]
+    fn test_remove_idle() {
+        let rooms = rooms(RoomsConfig {
+            retention_seconds: 0,
+            ..RoomsConfig::default()
+        });
+        let (room_id, room) = rooms.create_random().unwrap();
+        let guard = room.connect();
+
+        rooms.remove_idle();
+        assert!(rooms.get(&room_id).is_ok());
+
+        drop(guard);
+        rooms.remove_idle();
+        assert!(matches!(rooms.get(&room_id), Err(RoomError::NotFound)));
+    }
+
+    #[test]
+    fn test_limits() {
+        let rooms = rooms(RoomsConfig {
+            max_rooms: Some(1),
+            max_connections_per_room: Some(1),
+            ..RoomsConfig::default()
+        });
+
+        rooms.get_or_create("first").unwrap();
+        assert!(matches!(
+            rooms.get_or_create("second"),
+            Err(RoomError::TooManyRooms)
+        ));
+
+        let (_, guard) = rooms.connect("first").unwrap();
+        assert!(matches!(
+            rooms.connect("first"),
+            Err(RoomError::TooManyConnections)
+        ));
+
+        drop(guard);
+        assert!(rooms.connect("first").is_ok());
+    }
+
+    #[test]
+    fn test_fork() {
+        let rooms = rooms(RoomsConfig::default());
+        let source = rooms.get_or_create("source").unwrap();
+        source
+            .database
+            .connect(|_| {})
+            .send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: ciborium::Value::Integer(4.into()),
+                action: Action::Append,
+                request_id: None,
+            })
+            .unwrap();
+
+        let (room_id, copy) = rooms.fork(&source, Some("copy")).unwrap();
+        assert_eq!("copy", room_id);
+        assert_eq!(
+            source.database.snapshot().dump(),
+            copy.database.snapshot().dump()
+        );
+        assert_eq!(
+            SequenceNumber(1),
+            copy.database.snapshot().sequence_number()
+        );
+
+        assert!(matches!(
+            rooms.fork(&source, Some("copy")),
+            Err(RoomError::AlreadyExists)
+        ));
+        assert!(rooms.fork(&source, None).is_ok());
+    }
+
+    #[test]
+    fn test_directory_storage() {
+        let dir = std::env::temp_dir().join(format!("driftdb-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let rooms = Rooms::new(RoomsConfig::default(), Storage::Directory(dir.clone()));
+        let room = rooms.get_or_create("persisted").unwrap();
+        room.database
+            .connect(|_| {})
+            .send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: ciborium::Value::Integer(4.into()),
+                action: Action::Append,
+                request_id: None,
+            })
+            .unwrap();
+        rooms.flush();
+
+        let reloaded = Rooms::new(RoomsConfig::default(), Storage::Directory(dir.clone()));
+        let store = reloaded.get("persisted").unwrap().database.snapshot();
+        assert_eq!(1, store.dump()[&"foo".into()].len());
+        assert_eq!(1, store.sequence_number().0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_hand_off() {
+        let dir = std::env::temp_dir().join(format!("driftdb-test-{}", Uuid::new_v4()));
+        let cluster = |node_id: &str| {
+            let config = crate::config::ClusterConfig {
+                node_id: node_id.to_string(),
+                advertise_url: format!("http://{}:8080", node_id),
+                heartbeat_seconds: 1,
+                node_timeout_seconds: 10,
+                secret: "secret".to_string(),
+            };
+            let cluster = Arc::new(Cluster::new(&config, &dir).unwrap());
+            cluster.refresh().unwrap();
+            cluster
+        };
+        let rooms = |cluster: &Arc<Cluster>| {
+            Rooms::new(RoomsConfig::default(), Storage::Directory(dir.clone()))
+                .with_cluster(cluster.clone())
+        };
+        let push = |room: &Room| {
+            room.database
+                .connect(|_| {})
+                .send_message(&MessageToDatabase::Push {
+                    key: "foo".into(),
+                    value: ciborium::Value::Integer(4.into()),
+                    action: Action::Append,
+                    request_id: None,
+                })
+        };
+
+        let a = cluster("a");
+        let a_rooms = rooms(&a);
+        let b = cluster("b");
+        let b_rooms = rooms(&b);
+        a.refresh().unwrap();
+
+        // Pick a room which moves to b, but load it on a first, as if a had
+        // not yet noticed b joining.
+        let room_id = (0..)
+            .map(|i| format!("room-{}", i))
+            .find(|room_id| b.is_local(room_id))
+            .unwrap();
+        a_rooms.rooms.insert(
+            room_id.clone(),
+            Arc::new(a_rooms.new_room(&room_id, Store::default())),
+        );
+        a.acquire_lease(&room_id).unwrap();
+        let room = a_rooms.rooms.get(&room_id).unwrap().clone();
+        push(&room).unwrap();
+
+        // b waits for a to hand the room off.
+        assert!(matches!(
+            b_rooms.get_or_create(&room_id),
+            Err(RoomError::HandingOff)
+        ));
+
+        // After which the old copy takes no writes, and b has every change.
+        a_rooms.hand_off();
+        assert!(push(&room).is_err());
+        let store = b_rooms.get(&room_id).unwrap().database.snapshot();
+        assert_eq!(1, store.sequence_number().0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,28 +1,59 @@
-use crate::Opts;
+use crate::{
+    changes::changes,
+    cluster::{route_to_owner, Cluster},
+    config::{AuthConfig, Config, StorageConfig},
+    deflate::{WebSocket, WebSocketUpgrade},
+    events::events,
+    keys::{read_key, write_key},
+    replication::{promote, replicate, replication_status, Followers},
+    room::{ConnectionGuard, Room, RoomError, Rooms},
+    shutdown::{termination_signal, Shutdown, ShutdownSignal, CLOSE_CODE_SERVICE_RESTART},
+    storage::Storage,
+    webhooks::{create_webhook, delete_webhook, list_dead_letters, list_webhooks, WebhookSender},
+};
 use anyhow::Result;
 use axum::{
-    body::BoxBody,
-    extract::{ws::WebSocket, Host, Path, Query, State, WebSocketUpgrade},
-    response::Response,
-    routing::{get, post},
+    async_trait,
+    body::{BoxBody, Bytes},
+    extract::{FromRef, FromRequestParts, Host, Path, Query, State},
+    http::{request::Parts, Request},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Json, Router,
 };
-use dashmap::DashMap;
-use driftdb::{Database, MessageFromDatabase, MessageToDatabase};
-use hyper::http::header;
-use hyper::{Method, StatusCode};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use driftdb::{
+    chunks::UPLOAD_IDLE_TIMEOUT,
+    compression::{DEFAULT_COMPRESSION_THRESHOLD, MIN_COMPRESSION_THRESHOLD},
+    export::EXPORT_CONTENT_TYPE,
+    DebugMode, DebugOptions, Encoding, ErrorCode, MessageFromDatabase, MessageToDatabase, Store,
+};
+use futures_util::SinkExt;
+use hyper::http::{header, HeaderMap, HeaderValue};
+use hyper::Method;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+use std::{fmt::Debug, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tokio_tungstenite::tungstenite::{protocol::CloseFrame, Message};
 use tower_http::{
     cors::{AllowOrigin, CorsLayer},
-    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
+    trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer},
 };
-use tracing::Level;
-use uuid::Uuid;
+use tracing::{Level, Span};
+
+/// WebSocket close code for clients which speak an unsupported protocol.
+const CLOSE_CODE_PROTOCOL_ERROR: u16 = 1002;
+
+/// WebSocket close code for clients which fall too far behind the messages
+/// sent to them.
+const CLOSE_CODE_POLICY_VIOLATION: u16 = 1008;
 
 struct TypedWebSocket<Inbound: DeserializeOwned + Debug, Outbound: Serialize + Debug> {
     socket: WebSocket,
-    cbor: bool,
+    encoding: Encoding,
     _ph_inbound: std::marker::PhantomData<Inbound>,
     _ph_outbound: std::marker::PhantomData<Outbound>,
 }
@@ -30,35 +61,38 @@ struct TypedWebSocket<Inbound: DeserializeOwned + Debug, Outbound: Serialize + D
 impl<Inbound: DeserializeOwned + Debug, Outbound: Serialize + Debug>
     TypedWebSocket<Inbound, Outbound>
 {
-    pub fn new(socket: WebSocket, cbor: bool) -> Self {
+    pub fn new(socket: WebSocket, encoding: Encoding) -> Self {
         Self {
             socket,
-            cbor,
+            encoding,
             _ph_inbound: std::marker::PhantomData,
             _ph_outbound: std::marker::PhantomData,
         }
     }
 
     pub async fn recv(&mut self) -> Result<Option<Inbound>> {
-        let msg = self.socket.recv().await.transpose()?;
         loop {
-            match &msg {
+            let msg = self.socket.next().await.transpose()?;
+            match msg {
                 Some(msg) => match msg {
-                    axum::extract::ws::Message::Close(_) => {
+                    Message::Close(_) => {
                         return Ok(None);
                     }
-                    axum::extract::ws::Message::Ping(_) => {
-                        self.socket
-                            .send(axum::extract::ws::Message::Pong(vec![]))
-                            .await?;
-                    }
-                    axum::extract::ws::Message::Pong(_) => {}
-                    axum::extract::ws::Message::Binary(bytes) => {
-                        let msg = ciborium::de::from_reader(bytes.as_slice())?;
+                    // tungstenite answers pings itself.
+                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+                    Message::Binary(bytes) => {
+                        // Binary frames are CBOR unless another binary
+                        // encoding was negotiated.
+                        let encoding = if self.encoding.is_binary() {
+                            self.encoding
+                        } else {
+                            Encoding::Cbor
+                        };
+                        let msg = encoding.decode(&bytes)?;
                         return Ok(Some(msg));
                     }
-                    axum::extract::ws::Message::Text(msg) => {
-                        let msg = serde_json::from_str(msg)?;
+                    Message::Text(msg) => {
+                        let msg = Encoding::Json.decode(msg.as_bytes())?;
                         return Ok(Some(msg));
                     }
                 },
@@ -68,20 +102,25 @@ impl<Inbound: DeserializeOwned + Debug, Outbound: Serialize + Debug>
     }
 
     pub async fn send(&mut self, msg: Outbound) -> Result<()> {
-        if self.cbor {
-            let mut v = Vec::new();
-            ciborium::ser::into_writer(&msg, &mut v)?;
-
-            self.socket
-                .send(axum::extract::ws::Message::Binary(v))
-                .await?;
+        let bytes = self.encoding.encode(&msg)?;
+        let msg = if self.encoding.is_binary() {
+            Message::Binary(bytes)
         } else {
-            let msg = serde_json::to_string(&msg)?;
+            Message::Text(String::from_utf8(bytes).expect("JSON should be valid UTF-8."))
+        };
 
-            self.socket
-                .send(axum::extract::ws::Message::Text(msg))
-                .await?;
-        }
+        self.socket.send(msg).await?;
+
+        Ok(())
+    }
+
+    pub async fn close(&mut self, code: u16, reason: String) -> Result<()> {
+        self.socket
+            .send(Message::Close(Some(CloseFrame {
+                code: code.into(),
+                reason: reason.into(),
+            })))
+            .await?;
 
         Ok(())
     }
@@ -89,36 +128,45 @@ impl<Inbound: DeserializeOwned + Debug, Outbound: Serialize + Debug>
 
 async fn handle_socket(
     socket: WebSocket,
-    database: Arc<Database>,
+    room: Arc<Room>,
+    guard: ConnectionGuard,
     connection_spec: ConnectionQuery,
+    mut shutdown: ShutdownSignal,
+    session_grace: Duration,
 ) {
-    let (sender, mut receiver) = tokio::sync::mpsc::channel(32);
+    let database = &room.database;
     let mut socket: TypedWebSocket<MessageToDatabase, MessageFromDatabase> =
-        TypedWebSocket::new(socket, connection_spec.cbor);
-
-    let callback = move |message: &MessageFromDatabase| {
-        let result = sender.try_send(message.clone());
-
-        if let Err(err) = result {
-            tracing::error!(
-                ?err,
-                "Failed to send message to user, probably already closed."
-            );
-        }
-    };
+        TypedWebSocket::new(socket, connection_spec.encoding());
 
-    let conn = if connection_spec.debug {
-        database.connect_debug(callback)
+    let mut conn = if connection_spec.debug {
+        database.connect_debug_async(DebugOptions {
+            prefix: connection_spec.debug_prefix,
+            mode: connection_spec.debug_mode,
+        })
     } else {
-        database.connect(callback)
+        database.connect_async()
     };
+    let mut closed = room.closed();
+    let mut expire_uploads = tokio::time::interval_at(
+        tokio::time::Instant::now() + UPLOAD_IDLE_TIMEOUT,
+        UPLOAD_IDLE_TIMEOUT,
+    );
 
     loop {
         tokio::select! {
-            msg = receiver.recv() => {
+            msg = conn.next() => {
                 // We've received a message from the database; forward it to user.
 
-                let msg = msg.expect("Receiver should never be dropped before socket is closed.");
+                // The stream only ends if the client fell too far behind, in
+                // which case it has missed messages and should reconnect.
+                let Some(msg) = msg else {
+                    tracing::warn!("Client fell behind; closing connection.");
+
+                    let _ = socket
+                        .close(CLOSE_CODE_POLICY_VIOLATION, "Too many messages queued.".to_string())
+                        .await;
+                    break;
+                };
 
                 socket.send(msg).await.expect("Failed to send message to user.");
             }
@@ -127,12 +175,24 @@ async fn handle_socket(
 
                 match msg {
                     Ok(Some(msg)) => {
-                        if let Err(e) = conn.send_message(&msg) {
-                            tracing::error!(?e, "Failed to send message to database.");
+                        room.bump();
+
+                        if let Err(err) = conn.send(&msg).await {
+                            tracing::debug!(?err, "Message from user failed.");
 
-                            let _ = socket.send(MessageFromDatabase::Error {
-                                message: format!("Failed to send message to database: {}", e),
-                            }).await;
+                            let _ = socket.send(err.into_reply(msg.request_id())).await;
+                        }
+
+                        if conn.connection().is_rejected() {
+                            // Deliver the error explaining why before closing.
+                            while let Some(msg) = conn.try_recv() {
+                                let _ = socket.send(msg).await;
+                            }
+
+                            let _ = socket
+                                .close(CLOSE_CODE_PROTOCOL_ERROR, "Unsupported protocol version.".to_string())
+                                .await;
+                            break;
                         }
                     },
                     Ok(None) => {
@@ -143,6 +203,7 @@ async fn handle_socket(
                         tracing::warn!(?err, "Failed to receive message from user.");
 
                         let _ = socket.send(MessageFromDatabase::Error {
+                            code: ErrorCode::Parse,
                             message: format!("Failed to receive message from user: {}", err),
                         }).await;
 
@@ -150,8 +211,59 @@ async fn handle_socket(
                     }
                 };
             }
+            _ = expire_uploads.tick() => {
+                conn.connection().expire_idle_uploads();
+            }
+            retry_after = async {
+                tokio::select! {
+                    _ = shutdown.wait() => shutdown.retry_after,
+                    _ = closed.wait() => closed.retry_after,
+                }
+            } => {
+                // The server is shutting down, or has handed the room off to
+                // another node. Flush messages which are already queued for this
+                // client, then ask it to reconnect later.
+
+                while let Some(msg) = conn.try_recv() {
+                    if socket.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+
+                let _ = socket
+                    .close(
+                        CLOSE_CODE_SERVICE_RESTART,
+                        format!("retry_after={}", retry_after.as_secs()),
+                    )
+                    .await;
+
+                break;
+            }
         }
     }
+
+    // Keep the subscriptions of the connection so that the client can resume
+    // them by reconnecting within the grace window. The session is held
+    // before the connection is released, so the room is not reaped in the
+    // meantime.
+    let session = if session_grace.is_zero() {
+        None
+    } else {
+        database
+            .detach(conn.connection())
+            .map(|session| (session, room.hold_session()))
+    };
+
+    // The socket is closed, so it no longer counts as a connection.
+    drop(guard);
+
+    if let Some((session, hold)) = session {
+        tokio::spawn(async move {
+            tokio::time::sleep(session_grace).await;
+            room.database.expire_session(&session);
+            drop(hold);
+        });
+    }
 }
 
 #[derive(Deserialize)]
@@ -159,57 +271,297 @@ struct ConnectionQuery {
     #[serde(default)]
     debug: bool,
 
+    /// Debug key, for clients which can't set the `Authorization` header.
+    debug_key: Option<String>,
+
+    /// Only send debug connections keys starting with this prefix.
+    #[serde(default)]
+    debug_prefix: String,
+
+    #[serde(default)]
+    debug_mode: DebugMode,
+
+    /// Shorthand for `encoding=cbor`, kept for older clients.
     #[serde(default)]
     cbor: bool,
+
+    encoding: Option<Encoding>,
+
+    /// Size in bytes from which messages are compressed, if the client
+    /// negotiated `permessage-deflate`.
+    compress_threshold: Option<usize>,
 }
 
-type RoomMap = DashMap<String, Arc<Database>>;
+impl ConnectionQuery {
+    fn encoding(&self) -> Encoding {
+        match self.encoding {
+            Some(encoding) => encoding,
+            None if self.cbor => Encoding::Cbor,
+            None => Encoding::Json,
+        }
+    }
+
+    fn compress_threshold(&self) -> usize {
+        self.compress_threshold
+            .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD)
+            .max(MIN_COMPRESSION_THRESHOLD)
+    }
+}
 
 async fn post_message(
     Path(room_id): Path<String>,
-    State(room_map): State<Arc<RoomMap>>,
-    Json(msg): Json<MessageToDatabase>,
-) -> std::result::Result<Json<Option<MessageFromDatabase>>, StatusCode> {
-    let database = room_map.get(&room_id).ok_or(StatusCode::NOT_FOUND)?;
-    let conn = database.connect(|_| {});
-
-    let result = conn.send_message(&msg).unwrap();
-
-    Ok(Json(result))
+    State(rooms): State<Arc<Rooms>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> std::result::Result<Response, RoomError> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let request_encoding = match header(header::CONTENT_TYPE) {
+        Some(content_type) => Encoding::from_content_type(content_type).ok_or_else(|| {
+            RoomError::BadRequest(format!("Unsupported content type: {}", content_type))
+        })?,
+        None => Encoding::Json,
+    };
+    // Respond in JSON unless the client asks for another supported encoding.
+    let response_encoding = header(header::ACCEPT)
+        .and_then(Encoding::from_accept)
+        .unwrap_or(Encoding::Json);
+
+    let msg: MessageToDatabase = request_encoding
+        .decode(&body)
+        .map_err(|e| RoomError::BadRequest(e.to_string()))?;
+
+    let room = rooms.get(&room_id)?;
+    room.bump();
+    let conn = room.database.connect(|_| {});
+
+    let result = conn.send_message(&msg).map_err(RoomError::Database)?;
+    let bytes = response_encoding
+        .encode(&result)
+        .expect("Messages should encode.");
+
+    Ok((
+        [(header::CONTENT_TYPE, response_encoding.content_type())],
+        bytes,
+    )
+        .into_response())
 }
 
 async fn connection(
     Path(room_id): Path<String>,
     ws: WebSocketUpgrade,
-    State(room_map): State<Arc<RoomMap>>,
+    State(rooms): State<Arc<Rooms>>,
+    State(shutdown): State<ShutdownSignal>,
+    State(config): State<Arc<Config>>,
     Query(query): Query<ConnectionQuery>,
-) -> Response<BoxBody> {
-    let database = room_map
-        .get(&room_id)
-        .expect("Room should have been created before connection.")
-        .clone();
+    headers: HeaderMap,
+) -> std::result::Result<Response<BoxBody>, RoomError> {
+    if query.debug {
+        check_debug_key(&headers, query.debug_key.as_deref(), &config.auth)?;
+    }
+
+    let (room, guard) = rooms.connect(&room_id)?;
+
+    let session_grace = config.rooms.session_grace();
+
+    Ok(ws.on_upgrade(query.compress_threshold(), move |socket| {
+        handle_socket(socket, room, guard, query, shutdown, session_grace)
+    }))
+}
+
+/// The host (and port) that the client used to reach the server.
+///
+/// This defers to axum's [Host] extractor, except for HTTP/2 requests
+/// without a `Host` header, where [Host] would drop the port from the
+/// URI authority.
+struct RequestHost(String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestHost {
+    type Rejection = <Host as FromRequestParts<S>>::Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let has_host_header = [header::HOST, header::FORWARDED]
+            .iter()
+            .any(|name| parts.headers.contains_key(name))
+            || parts.headers.contains_key("x-forwarded-host");
+
+        if !has_host_header {
+            if let Some(authority) = parts.uri.authority() {
+                return Ok(RequestHost(authority.to_string()));
+            }
+        }
+
+        let Host(host) = Host::from_request_parts(parts, state).await?;
+        Ok(RequestHost(host))
+    }
+}
+
+/// Whether `token` is one of `keys`. Keys are compared in constant time, so
+/// that response times don't reveal how much of a key a caller has guessed.
+pub fn contains_key(keys: &[String], token: &str) -> bool {
+    keys.iter().fold(false, |found, key| {
+        found | bool::from(key.as_bytes().ct_eq(token.as_bytes()))
+    })
+}
+
+/// If the server is configured with API keys, require one of them as a
+/// bearer token.
+pub fn check_api_key(headers: &HeaderMap, auth: &AuthConfig) -> std::result::Result<(), RoomError> {
+    if auth.api_keys.is_empty() {
+        return Ok(());
+    }
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if contains_key(&auth.api_keys, token) => Ok(()),
+        _ => Err(RoomError::Unauthorized),
+    }
+}
+
+/// Require one of the server's debug keys, as a bearer token or as given in
+/// the query string.
+fn check_debug_key(
+    headers: &HeaderMap,
+    query_key: Option<&str>,
+    auth: &AuthConfig,
+) -> std::result::Result<(), RoomError> {
+    let token = query_key.or_else(|| {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+    });
+
+    match token {
+        Some(token) if contains_key(&auth.debug_keys, token) => Ok(()),
+        _ => Err(RoomError::Unauthorized),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct NewRoomRequest {
+    /// Caller-chosen room ID. If omitted, a random one is generated.
+    room: Option<String>,
+}
+
+async fn new_room(
+    RequestHost(hostname): RequestHost,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> std::result::Result<Json<RoomResult>, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    let request: NewRoomRequest = if body.is_empty() {
+        NewRoomRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| RoomError::BadRequest(e.to_string()))?
+    };
+
+    let room = match request.room {
+        Some(room) => {
+            if let Some(owner) = rooms.remote_owner(&room) {
+                return Err(RoomError::Moved(format!("{}/new", owner.url)));
+            }
 
-    ws.on_upgrade(move |socket| handle_socket(socket, database, query))
+            rooms.get_or_create(&room)?;
+            room
+        }
+        None => rooms.create_random()?.0,
+    };
+
+    let result = RoomResult::new(room, &hostname, config.tls.is_some());
+
+    Ok(Json(result))
+}
+
+async fn fork_room(
+    Path(room_id): Path<String>,
+    RequestHost(hostname): RequestHost,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> std::result::Result<Json<RoomResult>, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    let request: NewRoomRequest = if body.is_empty() {
+        NewRoomRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| RoomError::BadRequest(e.to_string()))?
+    };
+
+    let source = rooms.get(&room_id)?;
+    let (room, _) = rooms.fork(&source, request.room.as_deref())?;
+    tracing::info!(source = ?room_id, ?room, "Forked room.");
+
+    let result = RoomResult::new(room, &hostname, config.tls.is_some());
+
+    Ok(Json(result))
+}
+
+async fn export_room(
+    Path(room_id): Path<String>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> std::result::Result<Response, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    let room = rooms.get(&room_id)?;
+    let bytes = room.database.snapshot().export();
+
+    Ok(([(header::CONTENT_TYPE, EXPORT_CONTENT_TYPE)], bytes).into_response())
+}
+
+async fn import_room(
+    Path(room_id): Path<String>,
+    RequestHost(hostname): RequestHost,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> std::result::Result<Json<RoomResult>, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    let store = Store::import(&body).map_err(|e| RoomError::BadRequest(e.to_string()))?;
+    rooms.import(&room_id, store)?;
+    tracing::info!(?room_id, "Imported room.");
+
+    let result = RoomResult::new(room_id, &hostname, config.tls.is_some());
+
+    Ok(Json(result))
 }
 
-async fn new_room(Host(hostname): Host, State(room_map): State<Arc<RoomMap>>) -> Json<RoomResult> {
-    let room = Uuid::new_v4().to_string();
-    let database = Arc::new(Database::new());
-    room_map.insert(room.clone(), database);
+async fn put_room(
+    Path(room_id): Path<String>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    RequestHost(hostname): RequestHost,
+    headers: HeaderMap,
+) -> std::result::Result<Json<RoomResult>, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+    rooms.get_or_create(&room_id)?;
 
-    let result = RoomResult::new(room, &hostname);
+    let result = RoomResult::new(room_id, &hostname, config.tls.is_some());
 
-    Json(result)
+    Ok(Json(result))
 }
 
 async fn room(
     Path(room_id): Path<String>,
-    State(room_map): State<Arc<RoomMap>>,
-    Host(hostname): Host,
-) -> std::result::Result<Json<RoomResult>, StatusCode> {
-    let _ = room_map.get(&room_id).ok_or(StatusCode::NOT_FOUND)?;
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    RequestHost(hostname): RequestHost,
+) -> std::result::Result<Json<RoomResult>, RoomError> {
+    rooms.get(&room_id)?;
 
-    let result = RoomResult::new(room_id, &hostname);
+    let result = RoomResult::new(room_id, &hostname, config.tls.is_some());
 
     Ok(Json(result))
 }
@@ -222,9 +574,12 @@ struct RoomResult {
 }
 
 impl RoomResult {
-    fn new(room: String, hostname: &str) -> Self {
-        let socket_url = format!("ws://{}/room/{}/connect", hostname, room);
-        let http_url = format!("http://{}/room/{}/send", hostname, room);
+    fn new(room: String, hostname: &str, use_https: bool) -> Self {
+        let ws_protocol = if use_https { "wss" } else { "ws" };
+        let http_protocol = if use_https { "https" } else { "http" };
+
+        let socket_url = format!("{}://{}/room/{}/connect", ws_protocol, hostname, room);
+        let http_url = format!("{}://{}/room/{}/send", http_protocol, hostname, room);
 
         Self {
             room,
@@ -234,41 +589,240 @@ impl RoomResult {
     }
 }
 
-pub fn api_routes() -> Result<Router> {
+#[derive(Clone)]
+struct AppState {
+    rooms: Arc<Rooms>,
+    shutdown: ShutdownSignal,
+    config: Arc<Config>,
+    followers: Arc<Followers>,
+}
+
+impl FromRef<AppState> for Arc<Rooms> {
+    fn from_ref(state: &AppState) -> Self {
+        state.rooms.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Followers> {
+    fn from_ref(state: &AppState) -> Self {
+        state.followers.clone()
+    }
+}
+
+impl FromRef<AppState> for ShutdownSignal {
+    fn from_ref(state: &AppState) -> Self {
+        state.shutdown.clone()
+    }
+}
+
+pub fn api_routes(
+    rooms: Arc<Rooms>,
+    shutdown: ShutdownSignal,
+    config: Arc<Config>,
+    cluster: Option<Arc<Cluster>>,
+    followers: Arc<Followers>,
+) -> Result<Router> {
+    let allow_origin = if config.cors.allowed_origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .cors
+            .allowed_origins
+            .iter()
+            .map(|origin| HeaderValue::from_str(origin))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_headers(vec![
             header::AUTHORIZATION,
             header::ACCEPT,
             header::CONTENT_TYPE,
         ])
-        .allow_origin(AllowOrigin::any());
-
-    let room_map = RoomMap::new();
+        .allow_origin(allow_origin);
 
-    Ok(Router::new()
+    let mut router = Router::new()
         .route("/new", post(new_room))
         .route("/room/:room_id/connect", get(connection))
         .route("/room/:room_id/send", post(post_message))
-        .route("/room/:room_id", get(room))
-        .layer(cors)
-        .with_state(Arc::new(room_map)))
+        .route("/room/:room_id/fork", post(fork_room))
+        .route("/room/:room_id/export", get(export_room))
+        .route("/room/:room_id/import", post(import_room))
+        .route("/room/:room_id/events", get(events))
+        .route("/room/:room_id/changes", get(changes))
+        .route(
+            "/room/:room_id/webhooks",
+            get(list_webhooks).post(create_webhook),
+        )
+        .route(
+            "/room/:room_id/webhooks/dead_letters",
+            get(list_dead_letters),
+        )
+        .route(
+            "/room/:room_id/webhooks/:webhook_id",
+            delete(delete_webhook),
+        )
+        .route("/room/:room_id", get(room).put(put_room))
+        .route(
+            "/room/:room_id/key/*key",
+            get(read_key).put(write_key).post(write_key),
+        )
+        .route("/room/:room_id/replicate", get(replicate))
+        .route("/room/:room_id/replication", get(replication_status))
+        .route("/room/:room_id/promote", post(promote));
+
+    if let Some(cluster) = cluster {
+        router = router.layer(middleware::from_fn_with_state(
+            (cluster, shutdown.clone()),
+            route_to_owner,
+        ));
+    }
+
+    Ok(router.layer(cors).with_state(AppState {
+        rooms,
+        shutdown,
+        config,
+        followers,
+    }))
+}
+
+/// Like tower_http's `DefaultMakeSpan`, but without the query string, which
+/// can carry a debug key.
+fn make_span<B>(request: &Request<B>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri().path(),
+        version = ?request.version(),
+    )
 }
 
-pub async fn run_server(opts: &Opts) -> anyhow::Result<()> {
+pub async fn run_server(config: Config) -> anyhow::Result<()> {
+    let config = Arc::new(config);
     let trace_layer = TraceLayer::new_for_http()
-        .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+        .make_span_with(make_span)
         .on_request(DefaultOnRequest::new().level(Level::INFO))
         .on_response(DefaultOnResponse::new().level(Level::INFO));
 
-    let app = api_routes()?.layer(trace_layer);
-    let addr = SocketAddr::new(opts.host, opts.port);
+    let storage = Storage::new(&config.storage)?;
+    let webhooks = WebhookSender::spawn(config.webhooks.clone());
+    let mut rooms = Rooms::new(config.rooms.clone(), storage).with_webhooks(webhooks);
+
+    // Clustering is only valid with directory storage, which nodes share.
+    let cluster = match (&config.cluster, &config.storage) {
+        (Some(cluster_config), StorageConfig::Directory { path, .. }) => {
+            let cluster = Arc::new(Cluster::new(cluster_config, path)?);
+            cluster.refresh()?;
+            tracing::info!(node_id = ?cluster_config.node_id, "Joined cluster.");
+
+            rooms = rooms.with_cluster(cluster.clone());
+            Some(cluster)
+        }
+        _ => None,
+    };
+
+    let rooms = Arc::new(rooms);
+    tokio::spawn(rooms.clone().reap_idle_rooms());
+
+    if let StorageConfig::Directory {
+        flush_interval_seconds,
+        ..
+    } = &config.storage
+    {
+        let period = Duration::from_secs(*flush_interval_seconds);
+        tokio::spawn(rooms.clone().flush_periodically(period));
+    }
+
+    if let Some(cluster) = &cluster {
+        tokio::spawn(cluster.clone().run(rooms.clone()));
+    }
+
+    let shutdown = Shutdown::new(Duration::from_secs(config.shutdown.retry_seconds));
+    let followers = match &config.replication {
+        Some(replication) => {
+            tracing::info!(leader_url = ?replication.leader_url, "Following leader.");
+            Followers::start(replication, &rooms, shutdown.signal())?
+        }
+        None => Followers::default(),
+    };
+
+    let tls_config = match &config.tls {
+        Some(tls) => Some(RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?),
+        None => None,
+    };
+
+    let app = api_routes(
+        rooms.clone(),
+        shutdown.signal(),
+        config.clone(),
+        cluster.clone(),
+        Arc::new(followers),
+    )?
+    .layer(trace_layer);
+
+    let handle = Handle::new();
+    let mut servers = JoinSet::new();
+    for addr in &config.listen {
+        let addr = *addr;
+        let service = app.clone().into_make_service();
+
+        if let Some(tls_config) = &tls_config {
+            tracing::info!(?addr, "Server is listening (TLS).");
+            servers.spawn(
+                axum_server::bind_rustls(addr, tls_config.clone())
+                    .handle(handle.clone())
+                    .serve(service),
+            );
+        } else {
+            tracing::info!(?addr, "Server is listening.");
+            servers.spawn(
+                axum_server::bind(addr)
+                    .handle(handle.clone())
+                    .serve(service),
+            );
+        }
+    }
+
+    tokio::select! {
+        result = servers.join_next() => {
+            if let Some(result) = result {
+                result??;
+            }
+            return Err(anyhow::anyhow!("Server exited."));
+        }
+        _ = termination_signal() => {}
+    }
+
+    // Stop accepting new connections, and tell connected clients to reconnect later.
+    tracing::info!("Shutting down.");
+    handle.graceful_shutdown(None);
+    shutdown.begin();
+
+    let deadline = Duration::from_secs(config.shutdown.timeout_seconds);
+    let drained = tokio::time::timeout(deadline, async {
+        while servers.join_next().await.is_some() {}
+        rooms.wait_for_disconnect().await;
+    })
+    .await;
 
-    tracing::info!(?addr, "Server is listening.");
+    if drained.is_err() {
+        tracing::warn!("Shutdown deadline elapsed before all clients disconnected.");
+    }
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    // Persist anything written since the last periodic flush.
+    rooms.flush();
+
+    if let Some(cluster) = &cluster {
+        cluster.leave();
+    }
 
-    Err(anyhow::anyhow!("Server exited."))
+    Ok(())
 }
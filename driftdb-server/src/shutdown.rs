@@ -0,0 +1,82 @@
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// WebSocket close code sent to clients when the server shuts down,
+/// telling them to reconnect later ("Service Restart").
+pub const CLOSE_CODE_SERVICE_RESTART: u16 = 1012;
+
+/// Handle held by long-lived connections to find out when the server
+/// is shutting down.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+
+    /// How long clients are told to wait before reconnecting.
+    pub retry_after: Duration,
+}
+
+impl ShutdownSignal {
+    /// Wait until the server begins shutting down. If the server never
+    /// shuts down, this never returns.
+    pub async fn wait(&mut self) {
+        while !*self.receiver.borrow() {
+            if self.receiver.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    signal: ShutdownSignal,
+}
+
+impl Shutdown {
+    pub fn new(retry_after: Duration) -> Self {
+        let (sender, receiver) = watch::channel(false);
+
+        Self {
+            sender,
+            signal: ShutdownSignal {
+                receiver,
+                retry_after,
+            },
+        }
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        self.signal.clone()
+    }
+
+    /// Tell every holder of a [ShutdownSignal] that the server is shutting down.
+    pub fn begin(&self) {
+        // This only fails if there are no receivers, which is fine.
+        let _ = self.sender.send(true);
+    }
+}
+
+/// Wait for the process to be asked to terminate, either by SIGTERM or Ctrl-C.
+pub async fn termination_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl-C handler.");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler.")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
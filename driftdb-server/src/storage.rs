@@ -0,0 +1,109 @@
+use crate::config::StorageConfig;
+use anyhow::{Context, Result};
+use driftdb::{
+    types::{SequenceNumber, SequenceValue},
+    Key, Store,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+/// The on-disk representation of a room.
+#[derive(Serialize, Deserialize)]
+struct RoomSnapshot {
+    seq: SequenceNumber,
+    data: HashMap<Key, Vec<SequenceValue>>,
+}
+
+/// Where rooms are persisted between runs of the server.
+pub enum Storage {
+    Memory,
+    Directory(PathBuf),
+}
+
+impl Storage {
+    /// Set up the configured backend, creating the storage directory if
+    /// it does not exist.
+    pub fn new(config: &StorageConfig) -> Result<Self> {
+        match config {
+            StorageConfig::Memory => Ok(Storage::Memory),
+            StorageConfig::Directory { path, .. } => {
+                std::fs::create_dir_all(path).with_context(|| {
+                    format!("Could not create storage directory {}", path.display())
+                })?;
+
+                Ok(Storage::Directory(path.clone()))
+            }
+        }
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        !matches!(self, Storage::Memory)
+    }
+
+    fn room_path(&self, room_id: &str) -> Option<PathBuf> {
+        match self {
+            Storage::Memory => None,
+            // Room IDs are validated to be safe to use as file names.
+            Storage::Directory(path) => Some(path.join(format!("{}.cbor", room_id))),
+        }
+    }
+
+    /// Load a previously persisted room, if one exists.
+    pub fn load(&self, room_id: &str) -> Result<Option<Store>> {
+        let Some(path) = self.room_path(room_id) else {
+            return Ok(None);
+        };
+
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Could not read {}", path.display()))
+            }
+        };
+
+        let snapshot: RoomSnapshot = ciborium::de::from_reader(bytes.as_slice())
+            .with_context(|| format!("Could not decode {}", path.display()))?;
+
+        Ok(Some(Store::from_dump(snapshot.data, snapshot.seq)))
+    }
+
+    /// Persist the given state of a room, replacing any earlier version.
+    pub fn save(&self, room_id: &str, store: &Store) -> Result<()> {
+        let Some(path) = self.room_path(room_id) else {
+            return Ok(());
+        };
+
+        let snapshot = RoomSnapshot {
+            seq: store.sequence_number(),
+            data: store.dump(),
+        };
+
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&snapshot, &mut bytes)?;
+
+        // Write to a temporary file first so that a crash mid-write does not
+        // leave a truncated room behind.
+        let tmp_path = path.with_extension("cbor.tmp");
+        std::fs::write(&tmp_path, bytes)
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Remove a persisted room.
+    pub fn delete(&self, room_id: &str) -> Result<()> {
+        let Some(path) = self.room_path(room_id) else {
+            return Ok(());
+        };
+
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("Could not delete {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,624 @@
+//! Outbound webhooks, which notify external services whenever keys in a
+//! room change.
+
+use crate::{
+    config::{Config, WebhooksConfig},
+    room::{RoomError, Rooms},
+    server::check_api_key,
+};
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use driftdb::ApplyResult;
+use hmac::{Hmac, Mac};
+use hyper::{
+    body::Bytes,
+    client::{
+        connect::dns::{GaiResolver, Name},
+        HttpConnector,
+    },
+    header::{self, HeaderMap},
+    service::Service,
+    Body, Client, Request, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, Semaphore};
+use uuid::Uuid;
+
+/// Header carrying the hex-encoded HMAC-SHA256 of the request body,
+/// prefixed with `sha256=`.
+const SIGNATURE_HEADER: &str = "x-driftdb-signature";
+
+/// Header carrying the ID of the webhook being delivered.
+const WEBHOOK_ID_HEADER: &str = "x-driftdb-webhook-id";
+
+type HttpsClient = Client<HttpsConnector<HttpConnector<PublicResolver>>>;
+
+/// Whether an address is reachable on the public internet, as opposed to
+/// loopback, link-local, private or otherwise reserved for local use.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space, used by carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local addresses.
+                || (first & 0xfe00) == 0xfc00
+                // Link-local addresses.
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolves webhook hosts, dropping addresses which are not public. Since
+/// this runs on every connection, a host can't pass validation and then be
+/// pointed at a private address.
+#[derive(Clone)]
+struct PublicResolver {
+    inner: GaiResolver,
+    allow_private: bool,
+}
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.inner.call(name);
+        let allow_private = self.allow_private;
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving
+                .await?
+                .filter(|addr| allow_private || is_public(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Webhook host does not resolve to a public address.",
+                ));
+            }
+
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// A target which is sent every change to keys matching its prefix.
+#[derive(Clone, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+
+    /// Only changes to keys starting with this prefix are delivered.
+    pub prefix: String,
+
+    /// Key used to sign deliveries. Only returned when the webhook is created.
+    #[serde(skip_serializing)]
+    secret: String,
+}
+
+/// A delivery which still failed after the maximum number of attempts.
+#[derive(Clone, Serialize)]
+pub struct DeadLetter {
+    pub webhook_id: String,
+    pub url: String,
+    pub payload: serde_json::Value,
+    pub attempts: u32,
+    pub error: String,
+
+    /// Unix timestamp (in seconds) of the final attempt.
+    pub failed_at: u64,
+}
+
+struct DeadLetters {
+    letters: Mutex<VecDeque<DeadLetter>>,
+    limit: usize,
+}
+
+impl DeadLetters {
+    fn push(&self, letter: DeadLetter) {
+        let mut letters = self.letters.lock().unwrap();
+        letters.push_back(letter);
+        while letters.len() > self.limit {
+            letters.pop_front();
+        }
+    }
+}
+
+struct Delivery {
+    webhook: Webhook,
+    payload: serde_json::Value,
+    dead_letters: Arc<DeadLetters>,
+}
+
+impl Delivery {
+    /// Record the delivery as a dead letter.
+    fn fail(self, attempts: u32, error: String) {
+        let failed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        self.dead_letters.push(DeadLetter {
+            webhook_id: self.webhook.id,
+            url: self.webhook.url,
+            payload: self.payload,
+            attempts,
+            error,
+            failed_at,
+        });
+    }
+}
+
+/// Queue of deliveries, drained by a background task which makes the
+/// requests.
+#[derive(Clone)]
+pub struct WebhookSender {
+    sender: mpsc::Sender<Delivery>,
+    dead_letter_limit: usize,
+}
+
+impl WebhookSender {
+    pub fn spawn(config: WebhooksConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_size);
+        let dead_letter_limit = config.dead_letter_limit;
+
+        tokio::spawn(deliver_all(receiver, config));
+
+        Self {
+            sender,
+            dead_letter_limit,
+        }
+    }
+}
+
+/// The webhooks registered on one room.
+pub struct RoomWebhooks {
+    room_id: String,
+    webhooks: RwLock<Vec<Webhook>>,
+    dead_letters: Arc<DeadLetters>,
+
+    /// Where changes are queued for delivery. If absent, webhooks can be
+    /// registered but are never called.
+    sender: Option<WebhookSender>,
+}
+
+impl RoomWebhooks {
+    pub fn new(room_id: &str, sender: Option<WebhookSender>) -> Self {
+        let limit = sender
+            .as_ref()
+            .map(|sender| sender.dead_letter_limit)
+            .unwrap_or_default();
+
+        Self {
+            room_id: room_id.to_string(),
+            webhooks: RwLock::default(),
+            dead_letters: Arc::new(DeadLetters {
+                letters: Mutex::default(),
+                limit,
+            }),
+            sender,
+        }
+    }
+
+    fn register(&self, url: String, prefix: String, secret: String) -> Webhook {
+        let webhook = Webhook {
+            id: Uuid::new_v4().to_string(),
+            url,
+            prefix,
+            secret,
+        };
+
+        self.webhooks.write().unwrap().push(webhook.clone());
+
+        webhook
+    }
+
+    fn remove(&self, id: &str) -> bool {
+        let mut webhooks = self.webhooks.write().unwrap();
+        let len = webhooks.len();
+        webhooks.retain(|webhook| webhook.id != id);
+
+        webhooks.len() < len
+    }
+
+    fn list(&self) -> Vec<Webhook> {
+        self.webhooks.read().unwrap().clone()
+    }
+
+    fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters
+            .letters
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Queue delivery of a change to every webhook whose prefix matches the
+    /// changed key. This is called with the database locked, so it only
+    /// hands the work off.
+    pub fn notify(&self, result: &ApplyResult) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+
+        let webhooks = self.webhooks.read().unwrap();
+        let mut matching = webhooks
+            .iter()
+            .filter(|webhook| result.key.as_str().starts_with(&webhook.prefix))
+            .peekable();
+
+        if matching.peek().is_none() {
+            return;
+        }
+
+        let payload = json!({
+            "room": self.room_id,
+            "result": result,
+        });
+
+        for webhook in matching {
+            let delivery = Delivery {
+                webhook: webhook.clone(),
+                payload: payload.clone(),
+                dead_letters: self.dead_letters.clone(),
+            };
+
+            match sender.sender.try_send(delivery) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(delivery)) => {
+                    tracing::warn!(webhook_id = ?delivery.webhook.id, "Webhook queue is full.");
+                    delivery.fail(0, "The webhook queue was full.".to_string());
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    tracing::error!("Webhook delivery task has stopped.");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size.");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn deliver_all(mut receiver: mpsc::Receiver<Delivery>, config: WebhooksConfig) {
+    let mut http = HttpConnector::new_with_resolver(PublicResolver {
+        inner: GaiResolver::new(),
+        allow_private: config.allow_private_targets,
+    });
+    http.enforce_http(false);
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http);
+    let client: HttpsClient = Client::builder().build(connector);
+    let permits = Arc::new(Semaphore::new(config.max_concurrent_deliveries));
+    let config = Arc::new(config);
+
+    while let Some(delivery) = receiver.recv().await {
+        // Wait for a delivery to finish before taking on another, so that
+        // the queue fills up rather than the number of tasks growing.
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed.");
+        let client = client.clone();
+        let config = config.clone();
+
+        tokio::spawn(async move {
+            deliver(client, config, delivery).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Deliver a change to a webhook, retrying with exponential backoff. If
+/// every attempt fails, a dead letter is recorded on the room.
+async fn deliver(client: HttpsClient, config: Arc<WebhooksConfig>, delivery: Delivery) {
+    let body = Bytes::from(
+        serde_json::to_vec(&delivery.payload).expect("Payloads should serialize as JSON."),
+    );
+    let signature = sign(&delivery.webhook.secret, &body);
+    let timeout = Duration::from_secs(config.timeout_seconds);
+    let max_backoff = Duration::from_secs(config.max_backoff_seconds);
+    let mut backoff = Duration::from_secs(config.initial_backoff_seconds);
+
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+
+        let attempt = send(&client, &delivery.webhook, body.clone(), &signature);
+        let err = match tokio::time::timeout(timeout, attempt).await {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => err,
+            Err(_) => anyhow!("Timed out waiting for a response."),
+        };
+
+        if attempts >= config.max_attempts {
+            tracing::warn!(
+                webhook_id = ?delivery.webhook.id,
+                url = ?delivery.webhook.url,
+                ?err,
+                "Giving up on webhook delivery."
+            );
+
+            delivery.fail(attempts, format!("{:#}", err));
+
+            return;
+        }
+
+        tracing::info!(
+            webhook_id = ?delivery.webhook.id,
+            ?err,
+            ?backoff,
+            "Webhook delivery failed, retrying."
+        );
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+async fn send(client: &HttpsClient, webhook: &Webhook, body: Bytes, signature: &str) -> Result<()> {
+    let request = Request::post(&webhook.url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .header(WEBHOOK_ID_HEADER, &webhook.id)
+        .body(Body::from(body))?;
+
+    let response = client.request(request).await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Webhook responded with {}.", response.status()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhookRequest {
+    url: String,
+
+    #[serde(default)]
+    prefix: String,
+
+    /// Key used to sign deliveries. If omitted, one is generated.
+    secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct NewWebhookResponse {
+    #[serde(flatten)]
+    webhook: Webhook,
+    secret: String,
+}
+
+/// Check that a webhook URL is an absolute http or https URL, and that its
+/// host resolves only to public addresses unless `allow_private` is set.
+async fn validate_url(url: &str, allow_private: bool) -> std::result::Result<(), RoomError> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| RoomError::BadRequest(format!("Invalid webhook URL: {}", url)))?;
+
+    let (host, port) = match (uri.scheme_str(), uri.host()) {
+        (Some("http"), Some(host)) => (host, uri.port_u16().unwrap_or(80)),
+        (Some("https"), Some(host)) => (host, uri.port_u16().unwrap_or(443)),
+        _ => {
+            return Err(RoomError::BadRequest(
+                "Webhook URLs must be absolute http or https URLs.".to_string(),
+            ))
+        }
+    };
+
+    if allow_private {
+        return Ok(());
+    }
+
+    // IPv6 hosts are bracketed in URLs.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| RoomError::BadRequest(format!("Could not resolve {}.", host)))?
+        .collect();
+
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public(addr.ip())) {
+        return Err(RoomError::BadRequest(
+            "Webhook URLs must not point at loopback, link-local or private addresses.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn create_webhook(
+    Path(room_id): Path<String>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+    Json(request): Json<NewWebhookRequest>,
+) -> std::result::Result<Response, RoomError> {
+    // Webhooks make the server send requests on the caller's behalf, so
+    // they are only available to callers who can be identified.
+    if config.auth.api_keys.is_empty() {
+        return Err(RoomError::Forbidden(
+            "Webhooks require the server to be configured with API keys.".to_string(),
+        ));
+    }
+    check_api_key(&headers, &config.auth)?;
+    validate_url(&request.url, config.webhooks.allow_private_targets).await?;
+
+    let secret = match request.secret {
+        Some(secret) if secret.is_empty() => {
+            return Err(RoomError::BadRequest(
+                "Webhook secrets must not be empty.".to_string(),
+            ))
+        }
+        Some(secret) => secret,
+        None => Uuid::new_v4().simple().to_string(),
+    };
+
+    let room = rooms.get(&room_id)?;
+    let webhook = room
+        .webhooks
+        .register(request.url, request.prefix, secret.clone());
+
+    tracing::info!(?room_id, webhook_id = ?webhook.id, url = ?webhook.url, "Registered webhook.");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(NewWebhookResponse { webhook, secret }),
+    )
+        .into_response())
+}
+
+pub async fn list_webhooks(
+    Path(room_id): Path<String>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> std::result::Result<Json<Vec<Webhook>>, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    Ok(Json(rooms.get(&room_id)?.webhooks.list()))
+}
+
+pub async fn delete_webhook(
+    Path((room_id, webhook_id)): Path<(String, String)>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> std::result::Result<Response, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    if rooms.get(&room_id)?.webhooks.remove(&webhook_id) {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok((StatusCode::NOT_FOUND, "Webhook not found.").into_response())
+    }
+}
+
+pub async fn list_dead_letters(
+    Path(room_id): Path<String>,
+    State(rooms): State<Arc<Rooms>>,
+    State(config): State<Arc<Config>>,
+    headers: HeaderMap,
+) -> std::result::Result<Json<Vec<DeadLetter>>, RoomError> {
+    check_api_key(&headers, &config.auth)?;
+
+    Ok(Json(rooms.get(&room_id)?.webhooks.dead_letters()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // Example from RFC 4231, test case 2.
+        assert_eq!(
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            sign("Jefe", b"what do ya want for nothing?")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_url() {
+        assert!(validate_url("https://93.184.216.34/hook", false)
+            .await
+            .is_ok());
+        assert!(validate_url("http://localhost:3000", true).await.is_ok());
+        assert!(validate_url("ftp://example.com", true).await.is_err());
+        assert!(validate_url("/relative", true).await.is_err());
+
+        for url in [
+            "http://localhost:3000",
+            "http://127.0.0.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.1/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+        ] {
+            assert!(validate_url(url, false).await.is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn test_dead_letter_limit() {
+        let dead_letters = DeadLetters {
+            letters: Mutex::default(),
+            limit: 2,
+        };
+
+        for attempts in 1..=3 {
+            dead_letters.push(DeadLetter {
+                webhook_id: "hook".to_string(),
+                url: "https://example.com".to_string(),
+                payload: serde_json::Value::Null,
+                attempts,
+                error: "error".to_string(),
+                failed_at: 0,
+            });
+        }
+
+        let attempts: Vec<u32> = dead_letters
+            .letters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|letter| letter.attempts)
+            .collect();
+        assert_eq!(vec![2, 3], attempts);
+    }
+}
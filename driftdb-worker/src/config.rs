@@ -1,14 +1,50 @@
+use driftdb::HistoryConfig;
 use std::time::Duration;
 use worker::{Env, RouteContext};
 
 const HTTPS: &str = "HTTPS";
 const RETENTION_SECONDS: &str = "RETENTION_SECONDS";
 const PROTOCOL: &str = "PROTOCOL";
+const DEBUG_KEYS: &str = "DEBUG_KEYS";
+const HISTORY_MAX_VALUES: &str = "HISTORY_MAX_VALUES";
+const HISTORY_MAX_AGE: &str = "HISTORY_MAX_AGE";
+const SESSION_GRACE_SECONDS: &str = "SESSION_GRACE_SECONDS";
 
 #[derive(Clone)]
 pub struct Configuration {
     pub use_https: bool,
     pub retention: Duration,
+
+    /// Keys accepted for debug connections. If empty, debug connections
+    /// are refused.
+    pub debug_keys: Vec<String>,
+
+    /// If set, rooms retain values which are removed from streams.
+    pub history: Option<HistoryConfig>,
+
+    /// How long a client can take to reconnect and resume the subscriptions
+    /// of a lost connection. Zero disables resumption.
+    pub session_grace: Duration,
+}
+
+/// History is enabled by setting the maximum number of values to retain.
+fn parse_history(max_values: Option<String>, max_age: Option<String>) -> Option<HistoryConfig> {
+    let max_values = max_values?.parse::<usize>().ok().filter(|n| *n > 0)?;
+    let max_age = max_age.and_then(|d| d.parse::<u64>().ok());
+
+    Some(HistoryConfig {
+        max_values,
+        max_age,
+    })
+}
+
+/// Split a comma-separated list of keys.
+fn parse_keys(keys: &str) -> Vec<String> {
+    keys.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 impl Configuration {
@@ -24,10 +60,28 @@ impl Configuration {
             .and_then(|d| d.parse::<u64>().ok())
             .unwrap_or(60 * 60 * 24);
         let retention = Duration::from_secs(retention);
+        let debug_keys = ctx
+            .secret(DEBUG_KEYS)
+            .map(|d| parse_keys(&d.to_string()))
+            .unwrap_or_default();
+        let history = parse_history(
+            ctx.var(HISTORY_MAX_VALUES).ok().map(|d| d.to_string()),
+            ctx.var(HISTORY_MAX_AGE).ok().map(|d| d.to_string()),
+        );
+        let session_grace = ctx
+            .var(SESSION_GRACE_SECONDS)
+            .ok()
+            .map(|d| d.to_string())
+            .and_then(|d| d.parse::<u64>().ok())
+            .unwrap_or(30);
+        let session_grace = Duration::from_secs(session_grace);
 
         Configuration {
             use_https,
             retention,
+            debug_keys,
+            history,
+            session_grace,
         }
     }
 
@@ -43,10 +97,28 @@ impl Configuration {
             .and_then(|d| d.parse::<u64>().ok())
             .unwrap_or(60 * 60 * 24);
         let retention = Duration::from_secs(retention);
+        let debug_keys = ctx
+            .secret(DEBUG_KEYS)
+            .map(|d| parse_keys(&d.to_string()))
+            .unwrap_or_default();
+        let history = parse_history(
+            ctx.var(HISTORY_MAX_VALUES).ok().map(|d| d.to_string()),
+            ctx.var(HISTORY_MAX_AGE).ok().map(|d| d.to_string()),
+        );
+        let session_grace = ctx
+            .var(SESSION_GRACE_SECONDS)
+            .ok()
+            .map(|d| d.to_string())
+            .and_then(|d| d.parse::<u64>().ok())
+            .unwrap_or(30);
+        let session_grace = Duration::from_secs(session_grace);
 
         Configuration {
             use_https,
             retention,
+            debug_keys,
+            history,
+            session_grace,
         }
     }
 }
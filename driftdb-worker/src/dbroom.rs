@@ -1,27 +1,45 @@
 use crate::{
     config::Configuration,
-    cors,
+    cors, random_room_id, requested_room_id, room_result,
     state::{PersistedDb, WrappedState},
     websocket::WrappedWebSocket,
+    ROOM_ID_LENGTH,
 };
-use driftdb::{Database, MessageFromDatabase, MessageToDatabase};
-use std::collections::HashMap;
+use driftdb::{
+    chunks::UPLOAD_IDLE_TIMEOUT,
+    compression::{self, DEFAULT_COMPRESSION_THRESHOLD, MIN_COMPRESSION_THRESHOLD},
+    export::EXPORT_CONTENT_TYPE,
+    types::{validate_room_id, Action, SequenceNumber},
+    Compression, Database, DebugMode, DebugOptions, Encoding, ErrorCode, Key, MessageFromDatabase,
+    MessageToDatabase, Store,
+};
+use percent_encoding::percent_decode_str;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio_stream::StreamExt;
 use worker::{
     async_trait, console_warn, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures,
-    worker_sys, Env, Method, Request, Response, Result, WebSocketPair, WebsocketEvent,
+    worker_sys, Delay, Env, Headers, Method, Request, RequestInit, Response, Result, WebSocketPair,
+    WebsocketEvent,
 };
 
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// WebSocket close code for clients which speak an unsupported protocol.
+const CLOSE_CODE_PROTOCOL_ERROR: u16 = 1002;
+
 #[durable_object]
 pub struct DbRoom {
     db: PersistedDb,
+    configuration: Configuration,
+    env: Env,
 }
 
 async fn receive_websocket_events(
     server: WrappedWebSocket,
     db: Database,
-    debug: bool,
+    debug: Option<DebugOptions>,
     state: WrappedState,
+    session_grace: Duration,
 ) {
     let mut event_stream = server.socket.events().expect("could not open stream");
 
@@ -31,13 +49,25 @@ async fn receive_websocket_events(
             server.send(message).expect("could not send message");
         };
 
-        if debug {
-            db.connect_debug(callback)
-        } else {
-            db.connect(callback)
+        match debug {
+            Some(options) => db.connect_debug(options, callback),
+            None => db.connect(callback),
         }
     };
 
+    // Abandon chunked pushes which have stalled. The task ends once the
+    // connection is dropped.
+    let weak_conn = Arc::downgrade(&conn);
+    wasm_bindgen_futures::spawn_local(async move {
+        loop {
+            Delay::from(UPLOAD_IDLE_TIMEOUT).await;
+            match weak_conn.upgrade() {
+                Some(conn) => conn.expire_idle_uploads(),
+                None => break,
+            }
+        }
+    });
+
     while let Some(event) = event_stream.next().await {
         match event.expect("received error in websocket") {
             WebsocketEvent::Message(msg) => {
@@ -45,22 +75,42 @@ async fn receive_websocket_events(
                     if let Ok(message) = serde_json::from_str::<MessageToDatabase>(&text) {
                         // Reset the timeout for cleaning up the database.
                         state.bump_alarm().await.expect("Error bumping alarm");
-                        conn.send_message(&message).unwrap();
+                        if let Err(err) = conn.send_message(&message) {
+                            server
+                                .send(&err.into_reply(message.request_id()))
+                                .expect("could not send message");
+                        }
                     } else {
                         server
                             .send(&MessageFromDatabase::Error {
+                                code: ErrorCode::Parse,
                                 message: format!("Could not decode message: {}", text),
                             })
                             .unwrap();
                     }
                 } else if let Some(bytes) = msg.bytes() {
-                    if let Ok(message) = ciborium::from_reader(bytes.as_slice()) {
+                    // Binary messages are CBOR unless another binary encoding
+                    // was negotiated, or compressed in the negotiated encoding.
+                    let decoded = if compression::is_compressed(&bytes) {
+                        compression::decompress(&bytes)
+                            .and_then(|bytes| server.encoding.decode::<MessageToDatabase>(&bytes))
+                    } else if server.encoding.is_binary() {
+                        server.encoding.decode::<MessageToDatabase>(&bytes)
+                    } else {
+                        Encoding::Cbor.decode::<MessageToDatabase>(&bytes)
+                    };
+                    if let Ok(message) = decoded {
                         // Reset the timeout for cleaning up the database.
                         state.bump_alarm().await.expect("Error bumping alarm");
-                        conn.send_message(&message).unwrap();
+                        if let Err(err) = conn.send_message(&message) {
+                            server
+                                .send(&err.into_reply(message.request_id()))
+                                .expect("could not send message");
+                        }
                     } else {
                         server
                             .send(&MessageFromDatabase::Error {
+                                code: ErrorCode::Parse,
                                 message: format!("Could not decode message: {:?}", bytes),
                             })
                             .unwrap();
@@ -68,19 +118,257 @@ async fn receive_websocket_events(
                 } else {
                     console_warn!("Received unknown message type.");
                 }
+
+                if conn.is_rejected() {
+                    server
+                        .socket
+                        .close(
+                            Some(CLOSE_CODE_PROTOCOL_ERROR),
+                            Some("Unsupported protocol version."),
+                        )
+                        .expect("could not close websocket");
+                    break;
+                }
             }
             WebsocketEvent::Close(_) => {
                 break;
             }
         }
     }
+
+    // Keep the subscriptions of the connection so that the client can resume
+    // them by reconnecting within the grace window. The room is not cleaned
+    // up in the meantime.
+    if session_grace.is_zero() {
+        return;
+    }
+    if let Some(session) = db.detach(&conn) {
+        state
+            .bump_alarm_after(session_grace)
+            .await
+            .expect("Error bumping alarm");
+        Delay::from(session_grace).await;
+        db.expire_session(&session);
+    }
+}
+
+fn query_params(req: &Request) -> Result<HashMap<String, String>> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect())
+}
+
+fn header_contains(req: &Request, name: &str, needle: &str) -> Result<bool> {
+    Ok(req
+        .headers()
+        .get(name)?
+        .map(|value| value.contains(needle))
+        .unwrap_or(false))
+}
+
+/// Extract the (percent-decoded) key from a path of the form
+/// `/room/<room_id>/key/<key>`.
+fn key_from_path(path: &str) -> Option<String> {
+    let (_, rest) = path.strip_prefix("/room/")?.split_once('/')?;
+    let key = rest.strip_prefix("key/")?;
+    percent_decode_str(key)
+        .decode_utf8()
+        .ok()
+        .map(|key| key.into_owned())
 }
 
 impl DbRoom {
-    async fn connect(&mut self, req: Request) -> Result<Response> {
-        let WebSocketPair { client, server } = WebSocketPair::new()?;
-        server.accept()?;
+    async fn read_key(&mut self, req: Request, key: String) -> Result<Response> {
+        let seq = match query_params(&req)?.get("seq").map(|seq| seq.parse()) {
+            Some(Ok(seq)) => seq,
+            Some(Err(_)) => return Response::error("Invalid sequence number.", 400),
+            None => 0,
+        };
+
+        let db = self.db.get_db().await?;
+        let values = db.get(&Key::new(key), SequenceNumber(seq));
+
+        if header_contains(&req, "Accept", CBOR_CONTENT_TYPE)? {
+            let mut body = Vec::new();
+            ciborium::ser::into_writer(&values, &mut body)
+                .expect("Encoding values as CBOR should not fail.");
+
+            let mut headers = Headers::new();
+            headers.set("Content-Type", CBOR_CONTENT_TYPE)?;
+            Ok(Response::from_bytes(body)?.with_headers(headers))
+        } else {
+            Response::from_json(&values)
+        }
+    }
+
+    async fn write_key(&mut self, mut req: Request, key: String) -> Result<Response> {
+        let query = query_params(&req)?;
+        let seq = match query.get("seq").map(|seq| seq.parse()) {
+            Some(Ok(seq)) => Some(SequenceNumber(seq)),
+            Some(Err(_)) => return Response::error("Invalid sequence number.", 400),
+            None => None,
+        };
+        let action = match Action::from_parts(
+            query.get("action").map(|s| s.as_str()).unwrap_or("replace"),
+            seq,
+        ) {
+            Ok(action) => action,
+            Err(err) => return Response::error(err.to_string(), 400),
+        };
+
+        let body = req.bytes().await?;
+        let value: Result<ciborium::value::Value> =
+            if header_contains(&req, "Content-Type", CBOR_CONTENT_TYPE)? {
+                ciborium::de::from_reader(body.as_slice()).map_err(|e| e.to_string().into())
+            } else {
+                serde_json::from_slice(&body).map_err(|e| e.to_string().into())
+            };
+        let value = match value {
+            Ok(value) => value,
+            Err(err) => return Response::error(err.to_string(), 400),
+        };
+
+        // Reset the timeout for cleaning up the database.
+        self.db.state.bump_alarm().await?;
+
+        let db = self.db.get_db().await?;
+        let conn = db.connect(|_| {});
+        let result = conn.send_message(&MessageToDatabase::Push {
+            key: Key::new(key),
+            value,
+            action,
+            request_id: None,
+        });
+        if let Err(err) = result {
+            return Response::error(err.to_string(), err.code().http_status());
+        }
+
+        Ok(Response::empty()?.with_status(204))
+    }
+
+    /// Copy this room into a new room, by sending its contents to the new
+    /// room's `import` handler.
+    async fn fork(&mut self, mut req: Request) -> Result<Response> {
+        let room_id = match requested_room_id(&mut req).await {
+            Ok(Some(room_id)) => room_id,
+            Ok(None) => random_room_id(ROOM_ID_LENGTH),
+            Err(err) => return Response::error(err.to_string(), 400),
+        };
+
+        if let Err(err) = validate_room_id(&room_id) {
+            return Response::error(err.to_string(), 400);
+        }
+
+        let body = self.db.get_db().await?.snapshot().export();
+
+        let mut url = req.url()?;
+        url.set_path(&format!("/room/{}/import", room_id));
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(js_sys::Uint8Array::from(body.as_slice()).into()));
+
+        let stub = self
+            .env
+            .durable_object("DATABASE")?
+            .id_from_name(&room_id)?
+            .get_stub()?;
+        let response = stub
+            .fetch_with_request(Request::new_with_init(url.as_str(), &init)?)
+            .await?;
+        if response.status_code() != 200 {
+            return Ok(response);
+        }
+
+        room_result(req, &room_id, self.configuration.use_https)
+    }
+
+    /// Encode the contents of this room in the portable export format.
+    async fn export(&mut self) -> Result<Response> {
+        let bytes = self.db.get_db().await?.snapshot().export();
+
+        let mut headers = Headers::new();
+        headers.set("Content-Type", EXPORT_CONTENT_TYPE)?;
+
+        Ok(Response::from_bytes(bytes)?.with_headers(headers))
+    }
+
+    /// Fill this room with the contents of an exported room. Fails if this
+    /// room has ever been written to.
+    async fn import(&mut self, mut req: Request) -> Result<Response> {
+        let body = req.bytes().await?;
+        let store = match Store::import(&body) {
+            Ok(store) => store,
+            Err(err) => return Response::error(err.to_string(), 400),
+        };
+
+        // Restoring replaces everything in storage, so check storage itself
+        // as well as the sequence number, which relayed messages also advance.
+        let db = self.db.get_db().await?;
+        if db.snapshot().sequence_number() > SequenceNumber::default()
+            || !self.db.is_empty().await?
+        {
+            return Response::error("Room already exists.", 409);
+        }
+
+        self.db.restore(store).await?;
+
+        // Start the retention clock of the new room.
+        self.db.state.bump_alarm().await?;
+
+        let room_id = match req.path().split('/').nth(2) {
+            Some(room_id) => room_id.to_string(),
+            None => return Response::error("Bad Request", 400),
+        };
+        room_result(req, &room_id, self.configuration.use_https)
+    }
+
+    /// Handle a single message sent over HTTP, in the encoding given by the
+    /// `Content-Type` header, responding in the encoding given by `Accept`.
+    async fn send(&mut self, mut req: Request) -> Result<Response> {
+        let request_encoding = match req.headers().get("Content-Type")? {
+            Some(content_type) => match Encoding::from_content_type(&content_type) {
+                Some(encoding) => encoding,
+                None => {
+                    return Response::error(
+                        format!("Unsupported content type: {}", content_type),
+                        400,
+                    )
+                }
+            },
+            None => Encoding::Json,
+        };
+        // Respond in JSON unless the client asks for another supported encoding.
+        let response_encoding = req
+            .headers()
+            .get("Accept")?
+            .and_then(|accept| Encoding::from_accept(&accept))
+            .unwrap_or(Encoding::Json);
+
+        let body = req.bytes().await?;
+        let message: MessageToDatabase = match request_encoding.decode(&body) {
+            Ok(message) => message,
+            Err(err) => return Response::error(err.to_string(), 400),
+        };
 
+        let db = self.db.get_db().await?;
+        let conn = db.connect(|_| {});
+        let response = match conn.send_message(&message) {
+            Ok(response) => response,
+            Err(err) => return Response::error(err.to_string(), err.code().http_status()),
+        };
+
+        let body = response_encoding
+            .encode(&response)
+            .map_err(|err| worker::Error::RustError(err.to_string()))?;
+        let mut headers = Headers::new();
+        headers.set("Content-Type", response_encoding.content_type())?;
+
+        Ok(Response::from_bytes(body)?.with_headers(headers))
+    }
+
+    async fn connect(&mut self, req: Request) -> Result<Response> {
         let db = self.db.get_db().await?;
         let state = self.db.state.clone();
 
@@ -92,11 +380,78 @@ impl DbRoom {
             .collect();
 
         let debug = query.get("debug").map(|s| !s.is_empty()).unwrap_or(false);
-        let use_cbor = query.get("cbor").map(|s| !s.is_empty()).unwrap_or(false);
+        let encoding = match query.get("encoding") {
+            Some(name) => match Encoding::from_name(name) {
+                Ok(encoding) => encoding,
+                Err(err) => return Response::error(err.to_string(), 400),
+            },
+            // `cbor` is shorthand for `encoding=cbor`, kept for older clients.
+            None if query.get("cbor").map(|s| !s.is_empty()).unwrap_or(false) => Encoding::Cbor,
+            None => Encoding::Json,
+        };
+
+        let compression = if query
+            .get("compress")
+            .map(|s| !s.is_empty())
+            .unwrap_or(false)
+        {
+            let threshold = match query.get("compress_threshold") {
+                Some(threshold) => match threshold.parse::<usize>() {
+                    Ok(threshold) => threshold,
+                    Err(_) => return Response::error("Invalid compress_threshold.", 400),
+                },
+                None => DEFAULT_COMPRESSION_THRESHOLD,
+            };
+            Some(Compression {
+                threshold: threshold.max(MIN_COMPRESSION_THRESHOLD),
+            })
+        } else {
+            None
+        };
+
+        let debug = if debug {
+            // Browsers can't set headers on WebSocket requests, so the key
+            // may also be given in the query string.
+            let header = req.headers().get("Authorization")?;
+            let key = query.get("debug_key").cloned().or_else(|| {
+                header
+                    .as_deref()
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(str::to_string)
+            });
 
-        let server = WrappedWebSocket::new(server, use_cbor);
+            match key {
+                Some(key) if self.configuration.debug_keys.contains(&key) => {}
+                _ => return Response::error("Missing or invalid debug key.", 401),
+            }
 
-        wasm_bindgen_futures::spawn_local(receive_websocket_events(server, db, debug, state));
+            let mode = match query.get("debug_mode") {
+                Some(mode) => match DebugMode::from_name(mode) {
+                    Ok(mode) => mode,
+                    Err(err) => return Response::error(err.to_string(), 400),
+                },
+                None => DebugMode::default(),
+            };
+
+            Some(DebugOptions {
+                prefix: query.get("debug_prefix").cloned().unwrap_or_default(),
+                mode,
+            })
+        } else {
+            None
+        };
+
+        let WebSocketPair { client, server } = WebSocketPair::new()?;
+        server.accept()?;
+        let server = WrappedWebSocket::new(server, encoding, compression);
+
+        wasm_bindgen_futures::spawn_local(receive_websocket_events(
+            server,
+            db,
+            debug,
+            state,
+            self.configuration.session_grace,
+        ));
 
         Response::from_websocket(client)?.with_cors(&cors())
     }
@@ -107,23 +462,31 @@ impl DurableObject for DbRoom {
     fn new(state: State, env: Env) -> Self {
         let configuration = Configuration::from_env(&env);
         Self {
-            db: PersistedDb::new(state, configuration),
+            db: PersistedDb::new(state, configuration.clone()),
+            configuration,
+            env,
         }
     }
 
-    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
         let url = req.url()?;
+
+        if let Some(key) = key_from_path(url.path()) {
+            return match req.method() {
+                Method::Get => self.read_key(req, key).await,
+                Method::Put | Method::Post => self.write_key(req, key).await,
+                _ => Response::error("Method not allowed", 405),
+            };
+        }
+
         let (_, path) = url.path().rsplit_once('/').unwrap_or_default();
         let method = req.method();
         match (method, path) {
             (Method::Get, "connect") => self.connect(req).await,
-            (Method::Post, "send") => {
-                let db = self.db.get_db().await?;
-                let conn = db.connect(|_| {});
-                let message: MessageToDatabase = req.json().await?;
-                let response = conn.send_message(&message)?;
-                Response::from_json(&response)
-            }
+            (Method::Post, "fork") => self.fork(req).await,
+            (Method::Get, "export") => self.export().await,
+            (Method::Post, "import") => self.import(req).await,
+            (Method::Post, "send") => self.send(req).await,
             _ => Response::error("Room command not found", 404),
         }
     }
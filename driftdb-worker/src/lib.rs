@@ -1,6 +1,7 @@
 #![doc = include_str!("../README.md")]
 
 use config::Configuration;
+use driftdb::types::validate_room_id;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use worker::Router;
 use worker::{event, Cors, Env, Method, Request, Response, Result, RouteContext};
@@ -11,15 +12,20 @@ mod state;
 mod utils;
 mod websocket;
 
-const ROOM_ID_LENGTH: usize = 24;
+pub const ROOM_ID_LENGTH: usize = 24;
 
 pub fn cors() -> Cors {
     Cors::new()
-        .with_methods(vec![Method::Post, Method::Get, Method::Options])
+        .with_methods(vec![
+            Method::Post,
+            Method::Get,
+            Method::Put,
+            Method::Options,
+        ])
         .with_origins(vec!["*"])
 }
 
-fn room_result(req: Request, room_id: &str, use_https: bool) -> Result<Response> {
+pub fn room_result(req: Request, room_id: &str, use_https: bool) -> Result<Response> {
     let host = req
         .headers()
         .get("Host")?
@@ -47,7 +53,7 @@ pub fn handle_room(req: Request, ctx: RouteContext<()>) -> Result<Response> {
 }
 
 /// Generate a random alphanumeric room ID.
-fn random_room_id(length: usize) -> String {
+pub fn random_room_id(length: usize) -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
         .take(length)
@@ -55,13 +61,60 @@ fn random_room_id(length: usize) -> String {
         .collect()
 }
 
-pub fn handle_new_room(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+/// Read the optional caller-chosen room ID from the body of a `/new` request.
+pub async fn requested_room_id(req: &mut Request) -> Result<Option<String>> {
+    let body = req.text().await?;
+    if body.is_empty() {
+        return Ok(None);
+    }
+
+    let body: serde_json::Value = serde_json::from_str(&body)?;
+    match body.get("room") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(room_id)) => Ok(Some(room_id.clone())),
+        Some(_) => Err(worker::Error::RustError(
+            "Room ID must be a string.".to_string(),
+        )),
+    }
+}
+
+pub async fn handle_new_room(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
     let configuration = Configuration::from_ctx(&ctx);
-    let room_id = random_room_id(ROOM_ID_LENGTH);
+
+    let room_id = match requested_room_id(&mut req).await {
+        Ok(Some(room_id)) => room_id,
+        Ok(None) => random_room_id(ROOM_ID_LENGTH),
+        Err(err) => return Response::error(err.to_string(), 400),
+    };
+
+    if let Err(err) = validate_room_id(&room_id) {
+        return Response::error(err.to_string(), 400);
+    }
+
+    // Durable Objects are created on first use, so there is nothing to
+    // do here if the room already exists.
     room_result(req, &room_id, configuration.use_https)
 }
 
+pub fn handle_put_room(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let configuration = Configuration::from_ctx(&ctx);
+    if let Some(id) = ctx.param("room_id") {
+        if let Err(err) = validate_room_id(id) {
+            return Response::error(err.to_string(), 400);
+        }
+
+        room_result(req, id, configuration.use_https)
+    } else {
+        Response::error("Bad Request", 400)
+    }
+}
+
 pub async fn handle_room_request(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    // Handlers starting with an underscore are only for requests between rooms.
+    if matches!(ctx.param("handler"), Some(handler) if handler.starts_with('_')) {
+        return Response::error("Room command not found", 404);
+    }
+
     if let Some(id) = ctx.param("room_id") {
         let namespace = ctx.durable_object("DATABASE")?;
         let stub = namespace.id_from_name(id)?.get_stub()?;
@@ -79,8 +132,10 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
 
     let response = router
         .get("/", |_, _| Response::ok("DriftDB Worker service."))
-        .post("/new", handle_new_room)
+        .post_async("/new", handle_new_room)
         .get("/room/:room_id", handle_room)
+        .put("/room/:room_id", handle_put_room)
+        .on_async("/room/:room_id/key/*key", handle_room_request)
         .on_async("/room/:room_id/:handler", handle_room_request)
         .run(req, env)
         .await?;
@@ -5,7 +5,7 @@ use driftdb::{
     ApplyResult, Database, DeleteInstruction, Key, PushInstruction, Store, ValueLog,
 };
 use gloo_utils::format::JsValueSerdeExt;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
 use worker::{console_log, wasm_bindgen::JsValue, wasm_bindgen_futures};
 use worker::{ListOptions, Result, State};
 
@@ -26,9 +26,15 @@ impl WrappedState {
     }
 
     pub async fn bump_alarm(&self) -> Result<()> {
+        self.bump_alarm_after(Duration::ZERO).await
+    }
+
+    /// Like [WrappedState::bump_alarm], but start the retention period after
+    /// `delay`, e.g. once a disconnected session can no longer be resumed.
+    pub async fn bump_alarm_after(&self, delay: Duration) -> Result<()> {
         let storage = self.state.storage();
-        let retention_ms = self.configuration.retention.as_millis() as i64;
-        storage.set_alarm(retention_ms).await
+        let alarm_ms = (delay + self.configuration.retention).as_millis() as i64;
+        storage.set_alarm(alarm_ms).await
     }
 }
 
@@ -69,6 +75,10 @@ impl PersistedDb {
             }
         };
 
+        if let Some(history) = self.state.configuration.history {
+            db.enable_history(history);
+        }
+
         {
             let state = self.state.clone();
             db.set_replica_callback(move |apply_result: &ApplyResult| {
@@ -144,6 +154,37 @@ impl PersistedDb {
         Ok(self.db.clone().unwrap())
     }
 
+    /// Whether nothing has been written to the room's storage.
+    pub async fn is_empty(&self) -> Result<bool> {
+        let storage = self.state.state.storage();
+        let entries = storage
+            .list_with_options(ListOptions::new().limit(1))
+            .await?;
+
+        Ok(entries.size() == 0)
+    }
+
+    /// Replace the contents of the database, and of its storage, with `store`.
+    pub async fn restore(&mut self, store: Store) -> Result<()> {
+        let mut storage = self.state.state.storage();
+        storage.delete_all().await?;
+
+        for (key, values) in store.dump() {
+            for value in values {
+                let storage_key = KeyAndSeq::new(key.clone(), value.seq).to_string();
+
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(&value.value, &mut buffer).unwrap();
+
+                storage.put(&storage_key, &buffer).await?;
+            }
+        }
+
+        self.get_db().await?.reset(store);
+
+        Ok(())
+    }
+
     async fn load_store(&self, state: &State) -> Result<Store> {
         let storage = state.storage();
         let mut subjects = HashMap::<Key, ValueLog>::new();
@@ -156,7 +197,8 @@ impl PersistedDb {
 
             let (value, key) = read_key_value(&kv)?;
 
-            let key_and_seq = KeyAndSeq::from_str(&key)?;
+            let key_and_seq = KeyAndSeq::from_str(&key)
+                .map_err(|err| worker::Error::RustError(err.to_string()))?;
             max_seq = max_seq.max(key_and_seq.seq.0);
 
             subjects
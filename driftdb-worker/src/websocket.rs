@@ -1,4 +1,4 @@
-use driftdb::MessageFromDatabase;
+use driftdb::{Compression, Encoding, MessageFromDatabase};
 use worker::{Result, WebSocket};
 
 /// A raw WebSocket is not Send or Sync, but that doesn't matter because we are compiling
@@ -7,25 +7,35 @@ use worker::{Result, WebSocket};
 #[derive(Clone)]
 pub struct WrappedWebSocket {
     pub socket: WebSocket,
-    use_cbor: bool,
+    pub encoding: Encoding,
+    pub compression: Option<Compression>,
 }
 unsafe impl Send for WrappedWebSocket {}
 unsafe impl Sync for WrappedWebSocket {}
 
 impl WrappedWebSocket {
-    pub fn new(socket: WebSocket, use_cbor: bool) -> Self {
-        WrappedWebSocket { socket, use_cbor }
+    pub fn new(socket: WebSocket, encoding: Encoding, compression: Option<Compression>) -> Self {
+        WrappedWebSocket {
+            socket,
+            encoding,
+            compression,
+        }
     }
 
     pub fn send(&self, message: &MessageFromDatabase) -> Result<()> {
-        if self.use_cbor {
-            let mut buffer = Vec::new();
-            ciborium::ser::into_writer(&message, &mut buffer).map_err(|_| {
-                worker::Error::RustError("Error encoding message to CBOR.".to_string())
-            })?;
+        let buffer = self
+            .encoding
+            .encode(message)
+            .map_err(|err| worker::Error::RustError(err.to_string()))?;
+        let compressed = self
+            .compression
+            .and_then(|compression| compression.compress(&buffer));
+        if let Some(compressed) = compressed {
+            self.socket.send_with_bytes(&compressed)?;
+        } else if self.encoding.is_binary() {
             self.socket.send_with_bytes(&buffer)?;
         } else {
-            let message = serde_json::to_string(message)?;
+            let message = String::from_utf8(buffer).expect("JSON should be valid UTF-8.");
             self.socket.send_with_str(message)?;
         }
 
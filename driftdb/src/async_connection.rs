@@ -0,0 +1,180 @@
+//! A connection which delivers messages as a [Stream] rather than through a
+//! callback, for embedders using an async runtime.
+
+use crate::{error::Result, Connection, MessageFromDatabase, MessageToDatabase};
+use futures_channel::mpsc::{self, Receiver};
+use futures_core::Stream;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+/// Upper bound on the number of messages waiting to be read from an
+/// [AsyncConnection].
+pub const MAX_QUEUED_MESSAGES: usize = 1024;
+
+/// A connection to a [crate::Database], created with
+/// [crate::Database::connect_async]. Messages from the database, including
+/// replies to messages sent on this connection, are yielded by the stream.
+///
+/// The database delivers messages while holding its lock, so it can't wait
+/// for a slow reader. Instead, up to [MAX_QUEUED_MESSAGES] are buffered
+/// until they are read; if a reader falls further behind than that, the
+/// connection is overflowed and the stream ends, since the reader has missed
+/// messages.
+pub struct AsyncConnection {
+    connection: Arc<Connection>,
+    receiver: Receiver<MessageFromDatabase>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl AsyncConnection {
+    /// Create a connection with `connect`, given the callback it should
+    /// deliver messages through.
+    pub(crate) fn new<F>(connect: F) -> Self
+    where
+        F: FnOnce(Box<dyn Fn(&MessageFromDatabase) + Send + Sync>) -> Arc<Connection>,
+    {
+        // The channel has room for one message per sender on top of its
+        // buffer, and there is a single sender.
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_MESSAGES - 1);
+        let sender = Mutex::new(sender);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let connection = {
+            let overflowed = overflowed.clone();
+            connect(Box::new(move |message: &MessageFromDatabase| {
+                let mut sender = sender.lock().unwrap();
+                if let Err(err) = sender.try_send(message.clone()) {
+                    // The receiver is only gone once this connection has
+                    // been dropped, so the channel is full.
+                    if err.is_full() {
+                        overflowed.store(true, Ordering::SeqCst);
+                        sender.close_channel();
+                    }
+                }
+            }))
+        };
+
+        AsyncConnection {
+            connection,
+            receiver,
+            overflowed,
+        }
+    }
+
+    /// The underlying connection, e.g. to check its capabilities or pass to
+    /// [crate::Database::detach].
+    pub fn connection(&self) -> &Arc<Connection> {
+        &self.connection
+    }
+
+    /// Set if the reader fell too far behind, in which case the stream has
+    /// ended and the host should close the connection.
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::SeqCst)
+    }
+
+    /// Send a message to the database. Any reply is yielded by the stream.
+    ///
+    /// This never waits on I/O; it is async so that callers don't need to
+    /// change if the database becomes async internally.
+    pub async fn send(&self, message: &MessageToDatabase) -> Result<()> {
+        self.connection.send_message(message)?;
+        Ok(())
+    }
+
+    /// Take the next message if one is already waiting, without waiting for
+    /// one to arrive.
+    pub fn try_recv(&mut self) -> Option<MessageFromDatabase> {
+        if self.is_overflowed() {
+            return None;
+        }
+
+        self.receiver.try_next().ok().flatten()
+    }
+}
+
+impl Stream for AsyncConnection {
+    type Item = MessageFromDatabase;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Messages still buffered are dropped, since those after them are
+        // missing.
+        if self.is_overflowed() {
+            return Poll::Ready(None);
+        }
+
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_QUEUED_MESSAGES;
+    use crate::{types::Action, Database, MessageFromDatabase, MessageToDatabase};
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_async_connection() {
+        let db = Database::new();
+        let mut conn = db.connect_async();
+        let mut other = db.connect_async();
+
+        conn.send(&MessageToDatabase::Get {
+            key: "foo".into(),
+            seq: None,
+            request_id: None,
+        })
+        .await
+        .unwrap();
+        other
+            .send(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: ciborium::Value::Integer(4.into()),
+                action: Action::Append,
+                request_id: Some(1),
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            conn.next().await,
+            Some(MessageFromDatabase::Push { .. })
+        ));
+        assert!(matches!(
+            other.next().await,
+            Some(MessageFromDatabase::Ack { request_id: 1, .. })
+        ));
+        assert_eq!(None, conn.try_recv());
+    }
+
+    #[tokio::test]
+    async fn test_overflow() {
+        let db = Database::new();
+        let mut conn = db.connect_async();
+        let ping = MessageToDatabase::Ping {
+            nonce: None,
+            request_id: None,
+        };
+
+        for _ in 0..MAX_QUEUED_MESSAGES {
+            conn.send(&ping).await.unwrap();
+        }
+        assert!(!conn.is_overflowed());
+        assert!(matches!(
+            conn.next().await,
+            Some(MessageFromDatabase::Pong { .. })
+        ));
+
+        // A reader which falls too far behind misses the rest of the stream.
+        conn.send(&ping).await.unwrap();
+        conn.send(&ping).await.unwrap();
+        assert!(conn.is_overflowed());
+        assert_eq!(None, conn.next().await);
+        assert_eq!(None, conn.try_recv());
+    }
+}
@@ -0,0 +1,313 @@
+//! Transfer of values too large to send in a single message.
+//!
+//! A client pushes a large value as a series of
+//! [MessageToDatabase::PushChunk] messages, each carrying a slice of the CBOR
+//! encoding of the value, which the database reassembles before applying the
+//! push. Clients announcing [CHUNKS_CAPABILITY] receive large `Init` messages
+//! in the same way, as [MessageFromDatabase::InitChunk] messages.
+
+use crate::{
+    error::{Error, Result},
+    types::{Action, SequenceValue},
+    Encoding, Key, MessageFromDatabase, MessageToDatabase,
+};
+use ciborium::value::Value;
+use std::{collections::HashMap, time::Duration};
+
+/// Capability a client announces in its `Hello` message to receive large
+/// `Init` messages in chunks.
+pub const CHUNKS_CAPABILITY: &str = "chunks";
+
+/// `Init` messages larger than this many bytes are sent in chunks of at most
+/// this size.
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Upper bound on the bytes of chunked pushes buffered on a connection at
+/// once, and so on the size of a value pushed in chunks.
+pub const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// Upper bound on the number of chunked pushes in progress on a connection.
+const MAX_UPLOADS: usize = 4;
+
+/// How often hosts should call [crate::Connection::expire_idle_uploads]. An
+/// upload which receives no chunk between two calls is abandoned.
+pub const UPLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Split a push into chunks of at most `chunk_size` bytes, to be sent in
+/// order.
+pub fn split_push(
+    key: &Key,
+    value: &Value,
+    action: &Action,
+    upload_id: u64,
+    chunk_size: usize,
+) -> Result<Vec<MessageToDatabase>> {
+    let bytes = Encoding::Cbor.encode(value)?;
+    let chunks: Vec<&[u8]> = bytes.chunks(chunk_size.max(1)).collect();
+    let total = chunks.len() as u32;
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| MessageToDatabase::PushChunk {
+            key: key.clone(),
+            action: action.clone(),
+            upload_id,
+            index: index as u32,
+            total,
+            data: data.to_vec(),
+            request_id: None,
+        })
+        .collect())
+}
+
+/// Split the data of an `Init` message into chunks, if it is larger than
+/// [MAX_CHUNK_SIZE].
+pub(crate) fn split_init(key: &Key, data: &[SequenceValue]) -> Option<Vec<MessageFromDatabase>> {
+    let bytes = Encoding::Cbor.encode(&data).ok()?;
+    if bytes.len() <= MAX_CHUNK_SIZE {
+        return None;
+    }
+
+    let chunks: Vec<&[u8]> = bytes.chunks(MAX_CHUNK_SIZE).collect();
+    let total = chunks.len() as u32;
+
+    Some(
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| MessageFromDatabase::InitChunk {
+                key: key.clone(),
+                index: index as u32,
+                total,
+                data: data.to_vec(),
+            })
+            .collect(),
+    )
+}
+
+struct Upload {
+    key: Key,
+    action: Action,
+    total: u32,
+    received: u32,
+    data: Vec<u8>,
+
+    /// Whether a chunk has arrived since the last call to
+    /// [Uploads::expire_idle].
+    active: bool,
+}
+
+/// Chunked pushes in progress on a connection.
+#[derive(Default)]
+pub(crate) struct Uploads {
+    /// Uploads by upload ID.
+    uploads: HashMap<u64, Upload>,
+
+    /// Bytes buffered across all uploads.
+    size: usize,
+}
+
+impl Uploads {
+    /// Add a chunk to its upload. Once the last chunk has arrived, returns
+    /// the reassembled value. Any error abandons the upload.
+    pub fn receive(
+        &mut self,
+        upload_id: u64,
+        key: &Key,
+        action: &Action,
+        index: u32,
+        total: u32,
+        data: &[u8],
+    ) -> Result<Option<Value>> {
+        let result = self.add_chunk(upload_id, key, action, index, total, data);
+        if !matches!(result, Ok(None)) {
+            self.remove(upload_id);
+        }
+
+        result
+    }
+
+    /// Abandon uploads which have not received a chunk since the last call.
+    pub fn expire_idle(&mut self) {
+        let expired: Vec<u64> = self
+            .uploads
+            .iter()
+            .filter(|(_, upload)| !upload.active)
+            .map(|(upload_id, _)| *upload_id)
+            .collect();
+        for upload_id in expired {
+            self.remove(upload_id);
+        }
+
+        for upload in self.uploads.values_mut() {
+            upload.active = false;
+        }
+    }
+
+    fn remove(&mut self, upload_id: u64) {
+        if let Some(upload) = self.uploads.remove(&upload_id) {
+            self.size -= upload.data.len();
+        }
+    }
+
+    fn add_chunk(
+        &mut self,
+        upload_id: u64,
+        key: &Key,
+        action: &Action,
+        index: u32,
+        total: u32,
+        data: &[u8],
+    ) -> Result<Option<Value>> {
+        if index >= total {
+            return Err(Error::Validation(format!(
+                "Chunk {} is out of range for an upload of {} chunks.",
+                index, total
+            )));
+        }
+
+        if index == 0 {
+            // Restarting an upload discards what it had received.
+            self.remove(upload_id);
+            if self.uploads.len() >= MAX_UPLOADS {
+                return Err(Error::LimitExceeded(format!(
+                    "At most {} chunked pushes may be in progress at once.",
+                    MAX_UPLOADS
+                )));
+            }
+
+            self.uploads.insert(
+                upload_id,
+                Upload {
+                    key: key.clone(),
+                    action: action.clone(),
+                    total,
+                    received: 0,
+                    data: Vec::new(),
+                    active: true,
+                },
+            );
+        }
+
+        let upload = self.uploads.get_mut(&upload_id).ok_or_else(|| {
+            Error::Validation(format!("Upload {} has not been started.", upload_id))
+        })?;
+
+        if index != upload.received {
+            return Err(Error::Validation(format!(
+                "Expected chunk {} of upload {}, got chunk {}.",
+                upload.received, upload_id, index
+            )));
+        }
+
+        if total != upload.total || key != &upload.key || action != &upload.action {
+            return Err(Error::Validation(format!(
+                "Chunk {} of upload {} does not match the first chunk.",
+                index, upload_id
+            )));
+        }
+
+        if self.size + data.len() > MAX_UPLOAD_SIZE {
+            return Err(Error::LimitExceeded(format!(
+                "Chunked pushes in progress are limited to {} bytes in total.",
+                MAX_UPLOAD_SIZE
+            )));
+        }
+
+        upload.data.extend_from_slice(data);
+        upload.received += 1;
+        upload.active = true;
+        self.size += data.len();
+
+        if upload.received < upload.total {
+            return Ok(None);
+        }
+
+        Encoding::Cbor.decode(&upload.data).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uploads() {
+        let key = Key::new("foo".to_string());
+        let value = Value::Bytes(vec![7; 1000]);
+        let chunks = split_push(&key, &value, &Action::Replace, 1, 300).unwrap();
+        assert_eq!(4, chunks.len());
+
+        let mut uploads = Uploads::default();
+        let mut received = Vec::new();
+        for chunk in &chunks {
+            match chunk {
+                MessageToDatabase::PushChunk {
+                    key,
+                    action,
+                    upload_id,
+                    index,
+                    total,
+                    data,
+                    ..
+                } => received.push(
+                    uploads
+                        .receive(*upload_id, key, action, *index, *total, data)
+                        .unwrap(),
+                ),
+                _ => panic!("Expected a chunk, got {:?}", chunk),
+            }
+        }
+        assert_eq!(vec![None, None, None, Some(value)], received);
+
+        // Chunks must arrive in order.
+        assert!(uploads
+            .receive(2, &key, &Action::Replace, 0, 3, &[1])
+            .unwrap()
+            .is_none());
+        assert!(uploads
+            .receive(2, &key, &Action::Replace, 2, 3, &[1])
+            .is_err());
+        assert!(uploads
+            .receive(2, &key, &Action::Replace, 1, 3, &[1])
+            .is_err());
+        assert_eq!(0, uploads.size);
+    }
+
+    #[test]
+    fn test_upload_limits() {
+        let key = Key::new("foo".to_string());
+        let chunk = vec![0; MAX_UPLOAD_SIZE / 2];
+        let mut uploads = Uploads::default();
+
+        // The byte budget is shared by all uploads on a connection.
+        for upload_id in [1, 2] {
+            assert!(uploads
+                .receive(upload_id, &key, &Action::Replace, 0, 3, &chunk)
+                .unwrap()
+                .is_none());
+        }
+        assert!(matches!(
+            uploads.receive(3, &key, &Action::Replace, 0, 3, &[1]),
+            Err(Error::LimitExceeded(_))
+        ));
+
+        // Uploads survive one sweep, and are abandoned if no chunk arrives
+        // before the next.
+        uploads.expire_idle();
+        assert!(uploads
+            .receive(2, &key, &Action::Replace, 1, 3, &[])
+            .unwrap()
+            .is_none());
+        uploads.expire_idle();
+        assert_eq!(vec![&2], uploads.uploads.keys().collect::<Vec<_>>());
+        assert_eq!(chunk.len(), uploads.size);
+
+        // Which frees up room for other uploads.
+        assert!(uploads
+            .receive(3, &key, &Action::Replace, 0, 3, &[1])
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,105 @@
+//! Application-level compression of messages, for WebSocket connections
+//! which can't negotiate `permessage-deflate`, such as the worker's.
+//!
+//! Compressed messages are zlib streams sent as binary frames. Messages are
+//! maps in every encoding, so an uncompressed message never begins with the
+//! zlib header byte, and the two can be told apart.
+
+use crate::error::{Error, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder};
+use std::io::{Read, Write};
+
+/// Messages smaller than this many bytes are sent uncompressed by default.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Lower bound on the threshold a client may ask for, below which messages
+/// would rarely get any smaller and compressing them is wasted work.
+pub const MIN_COMPRESSION_THRESHOLD: usize = 128;
+
+/// Upper bound on the size of a decompressed message.
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// First byte of a zlib stream with a 32 KiB window.
+const ZLIB_HEADER: u8 = 0x78;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// Messages smaller than this many bytes are sent uncompressed.
+    pub threshold: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl Compression {
+    /// Compress an encoded message, unless it is below the threshold or
+    /// doesn't get any smaller.
+    pub fn compress(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        if bytes.len() < self.threshold {
+            return None;
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).ok()?;
+        let compressed = encoder.finish().ok()?;
+
+        (compressed.len() < bytes.len()).then_some(compressed)
+    }
+}
+
+pub fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&ZLIB_HEADER)
+}
+
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    ZlibDecoder::new(bytes)
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| Error::Parse(e.to_string()))?;
+
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(Error::LimitExceeded(format!(
+            "Decompressed messages are limited to {} bytes.",
+            MAX_DECOMPRESSED_SIZE
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encoding, Key, MessageFromDatabase};
+    use ciborium::value::Value;
+
+    #[test]
+    fn test_compress() {
+        let message = MessageFromDatabase::Push {
+            key: Key::new("foo".to_string()),
+            value: Value::Text("a".repeat(4096)),
+            seq: crate::types::SequenceNumber(1),
+        };
+        let compression = Compression::default();
+
+        for encoding in [Encoding::Json, Encoding::Cbor, Encoding::MessagePack] {
+            let bytes = encoding.encode(&message).unwrap();
+            assert!(!is_compressed(&bytes));
+
+            let compressed = compression.compress(&bytes).unwrap();
+            assert!(is_compressed(&compressed));
+            assert!(compressed.len() < bytes.len());
+            assert_eq!(bytes, decompress(&compressed).unwrap());
+        }
+
+        // Small messages are left alone.
+        assert_eq!(None, compression.compress(b"{}"));
+        assert!(decompress(&[ZLIB_HEADER, 0]).is_err());
+    }
+}
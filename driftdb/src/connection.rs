@@ -1,14 +1,49 @@
 use crate::{
+    chunks::{self, Uploads, CHUNKS_CAPABILITY},
     db::DatabaseInner,
-    types::{MessageFromDatabase, MessageToDatabase},
+    error::{Error, Result},
+    types::{MessageFromDatabase, MessageToDatabase, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION},
+    Key,
+};
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
 };
-use std::sync::{Arc, Mutex, Weak};
 
 type Callback = Arc<Box<dyn Fn(&MessageFromDatabase) + Send + Sync>>;
 
+/// Generate a session token for the given connection. Tokens only need to
+/// be hard to guess by accident, since any client of a room can read it.
+fn session_token(id: u64) -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(id);
+    format!("{:016x}{}", hasher.finish(), id)
+}
+
 pub struct Connection {
     pub callback: Callback,
     database: Weak<Mutex<DatabaseInner>>,
+    id: u64,
+
+    /// Capabilities announced by the client in its `Hello` message.
+    capabilities: Mutex<Vec<String>>,
+
+    /// Set if the client announced an unsupported protocol version, in which
+    /// case the host should close the connection.
+    rejected: AtomicBool,
+
+    /// Keys this connection has subscribed to.
+    subscriptions: Mutex<HashSet<Key>>,
+
+    /// Session token, issued in response to a `Hello` message.
+    session: Mutex<Option<String>>,
+
+    /// Chunked pushes in progress.
+    uploads: Mutex<Uploads>,
 }
 
 impl Connection {
@@ -16,37 +51,210 @@ impl Connection {
     where
         F: Fn(&MessageFromDatabase) + 'static + Send + Sync,
     {
+        let id = database.lock().unwrap().next_connection_id();
+
         Connection {
             callback: Arc::new(Box::new(callback)),
             database: Arc::downgrade(&database),
+            id,
+            capabilities: Mutex::new(Vec::new()),
+            rejected: AtomicBool::new(false),
+            subscriptions: Mutex::new(HashSet::new()),
+            session: Mutex::new(None),
+            uploads: Mutex::new(Uploads::default()),
+        }
+    }
+
+    /// An identifier for this connection, unique within its database.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c == capability)
+    }
+
+    /// Abandon chunked pushes which have not received a chunk since the
+    /// last call. Hosts should call this every
+    /// [chunks::UPLOAD_IDLE_TIMEOUT].
+    pub fn expire_idle_uploads(&self) {
+        self.uploads.lock().unwrap().expire_idle();
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    pub fn session(&self) -> Option<String> {
+        self.session.lock().unwrap().clone()
+    }
+
+    pub fn subscriptions(&self) -> Vec<Key> {
+        self.subscriptions.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Send an `Init` message in chunks if the client accepts them and it is
+    /// too large to send whole. Otherwise, returns the message.
+    fn chunk_init(&self, init: MessageFromDatabase) -> Option<MessageFromDatabase> {
+        if !self.has_capability(CHUNKS_CAPABILITY) {
+            return Some(init);
+        }
+
+        if let MessageFromDatabase::Init { key, data } = &init {
+            if let Some(chunks) = chunks::split_init(key, data) {
+                for chunk in &chunks {
+                    (self.callback)(chunk);
+                }
+                return None;
+            }
         }
+
+        Some(init)
     }
 
+    /// Handle a message from the client. Replies are passed to the
+    /// connection's callback, and the main one is also returned. If the
+    /// message fails, the error is returned instead, and hosts should report
+    /// it to the client with [Error::into_reply].
     pub fn send_message(
         self: &Arc<Self>,
         message: &MessageToDatabase,
-    ) -> Result<Option<MessageFromDatabase>, &str> {
-        let db_lock = self.database.upgrade().ok_or("Database is gone")?;
+    ) -> Result<Option<MessageFromDatabase>> {
+        let db_lock = self.database.upgrade().ok_or(Error::DatabaseGone)?;
         let mut database = db_lock.lock().unwrap();
 
+        let mut seq = None;
         let result = match message {
-            MessageToDatabase::Push { key, value, action } => database.push(key, value, action),
-            MessageToDatabase::Get { seq, key } => {
+            MessageToDatabase::Push {
+                key, value, action, ..
+            } => database
+                .push(key, value, action)
+                .map(|(assigned, response)| {
+                    seq = Some(assigned);
+                    response
+                }),
+            MessageToDatabase::Get { seq, key, .. } => {
+                self.subscriptions.lock().unwrap().insert(key.clone());
                 database.subscribe(key, Arc::downgrade(self));
                 if let Some(seq) = seq {
                     // Send prior events on the stream if sequence number is provided.
-                    database.get(key, *seq)
+                    Ok(database
+                        .get(key, *seq)
+                        .and_then(|init| self.chunk_init(init)))
                 } else {
-                    None
+                    Ok(None)
                 }
             }
-            MessageToDatabase::Ping { nonce } => Some(MessageFromDatabase::Pong { nonce: *nonce }),
-        };
+            MessageToDatabase::GetAt { key, seq, .. } => database.get_at(key, *seq).map(Some),
+            MessageToDatabase::PushChunk {
+                key,
+                action,
+                upload_id,
+                index,
+                total,
+                data,
+                ..
+            } => {
+                let value = self
+                    .uploads
+                    .lock()
+                    .unwrap()
+                    .receive(*upload_id, key, action, *index, *total, data);
+                match value {
+                    Ok(Some(value)) => {
+                        database
+                            .push(key, &value, action)
+                            .map(|(assigned, response)| {
+                                seq = Some(assigned);
+                                response
+                            })
+                    }
+                    Ok(None) => Ok(None),
+                    Err(err) => Err(err),
+                }
+            }
+            MessageToDatabase::Ping { nonce, .. } => {
+                Ok(Some(MessageFromDatabase::Pong { nonce: *nonce }))
+            }
+            MessageToDatabase::Hello {
+                version,
+                capabilities,
+                session,
+                seq,
+                ..
+            } => {
+                if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(version) {
+                    *self.capabilities.lock().unwrap() = capabilities.clone();
 
-        if let Some(response) = result.clone() {
-            (self.callback)(&response);
+                    // Take over the subscriptions of the earlier session, and
+                    // send the values the client missed.
+                    let resumed = session
+                        .as_ref()
+                        .and_then(|token| database.take_session(token));
+                    if let Some(keys) = &resumed {
+                        let seq = seq.unwrap_or_default();
+                        for key in keys {
+                            self.subscriptions.lock().unwrap().insert(key.clone());
+                            database.subscribe(key, Arc::downgrade(self));
+                            if let Some(init) = database
+                                .get(key, seq)
+                                .and_then(|init| self.chunk_init(init))
+                            {
+                                (self.callback)(&init);
+                            }
+                        }
+                    }
+
+                    // A new token is issued even when resuming, so that the
+                    // expiry of the earlier session can't affect this one.
+                    let token = session_token(self.id);
+                    *self.session.lock().unwrap() = Some(token.clone());
+
+                    Ok(Some(MessageFromDatabase::Welcome {
+                        version: PROTOCOL_VERSION,
+                        connection_id: self.id,
+                        features: database.features(),
+                        session: token,
+                        resumed: resumed.is_some(),
+                    }))
+                } else {
+                    self.rejected.store(true, Ordering::SeqCst);
+
+                    Err(Error::Validation(format!(
+                        "Unsupported protocol version {}; this server supports versions {} to {}.",
+                        version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    )))
+                }
+            }
         };
 
-        Ok(result)
+        let result = result?;
+
+        // If the client asked for an acknowledgement, it follows the reply.
+        let ack = message
+            .request_id()
+            .map(|request_id| MessageFromDatabase::Ack {
+                request_id,
+                seq,
+                error: None,
+                code: None,
+            });
+
+        for response in result.iter().chain(&ack) {
+            (self.callback)(response);
+        }
+
+        // Callers without a callback, like the HTTP endpoint, only see the
+        // return value. The acknowledgement of a push carries the sequence
+        // number it was assigned; other messages return any reply instead.
+        if seq.is_some() {
+            Ok(ack.or(result))
+        } else {
+            Ok(result.or(ack))
+        }
     }
 }
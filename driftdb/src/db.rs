@@ -1,10 +1,14 @@
+#[cfg(feature = "async")]
+use crate::AsyncConnection;
 use crate::{
     connection::Connection,
-    store::{ApplyResult, Store},
-    types::{Action, MessageFromDatabase, SequenceNumber},
+    error::{Error, Result},
+    store::{ApplyResult, HistoryConfig, Store},
+    types::{Action, MessageFromDatabase, SequenceNumber, SequenceValue},
     Key,
 };
 use ciborium::Value;
+use serde::Deserialize;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex, Weak},
@@ -12,64 +16,151 @@ use std::{
 
 type ReplicaCallback = Arc<Box<dyn Fn(&ApplyResult) + Send + Sync>>;
 
+/// How a debug connection is notified of changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugMode {
+    /// Send the full state of a key (an `Init` message) whenever it changes.
+    #[default]
+    Full,
+
+    /// Send each value (a `Push` message) as it is pushed. Changes which
+    /// don't push a value, like compactions, are sent as in `Full` mode.
+    Delta,
+}
+
+impl DebugMode {
+    pub fn from_name(name: &str) -> Result<DebugMode> {
+        match name {
+            "full" => Ok(DebugMode::Full),
+            "delta" => Ok(DebugMode::Delta),
+            _ => Err(Error::Parse(
+                "Unknown debug mode; expected full or delta.".to_string(),
+            )),
+        }
+    }
+}
+
+/// Which changes a debug connection receives, and how.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugOptions {
+    /// Only keys starting with this prefix are sent.
+    pub prefix: String,
+
+    pub mode: DebugMode,
+}
+
+impl DebugOptions {
+    fn matches(&self, key: &Key) -> bool {
+        key.as_str().starts_with(&self.prefix)
+    }
+}
+
 #[derive(Default)]
 pub struct DatabaseInner {
     subscriptions: HashMap<Key, Vec<Weak<Connection>>>,
-    debug_connections: Vec<Weak<Connection>>,
+    debug_connections: Vec<(Weak<Connection>, DebugOptions)>,
     replica_callback: Option<ReplicaCallback>,
     store: Store,
+
+    /// If set, pushes from connections are rejected. Changes can still be
+    /// made through [Database::apply_replicated].
+    read_only: bool,
+
+    /// ID to assign to the next connection.
+    next_connection_id: u64,
+
+    /// Keys subscribed to by connections which have gone away, by session
+    /// token, until they are resumed or expire.
+    detached_sessions: HashMap<String, Vec<Key>>,
 }
 
 impl DatabaseInner {
+    pub(crate) fn next_connection_id(&mut self) -> u64 {
+        self.next_connection_id += 1;
+        self.next_connection_id
+    }
+
+    /// Optional features of the protocol available on this database,
+    /// announced to clients which send a `Hello` message.
+    pub fn features(&self) -> Vec<String> {
+        let mut features = vec![
+            "ack".to_string(),
+            "resume".to_string(),
+            "chunks".to_string(),
+        ];
+        if self.store.history_config().is_some() {
+            features.push("history".to_string());
+        }
+
+        features
+    }
+
+    /// Apply a push, returning the sequence number assigned to the change
+    /// and the message (if any) to send back to the pushing connection.
     pub fn push(
         &mut self,
         key: &Key,
         value: &Value,
         action: &Action,
-    ) -> Option<MessageFromDatabase> {
+    ) -> Result<(SequenceNumber, Option<MessageFromDatabase>)> {
+        if self.read_only {
+            return Err(Error::Permission("This database is read-only.".to_string()));
+        }
+
         let result = self.store.apply(key, value.clone(), action);
 
-        if !self.debug_connections.is_empty() {
-            if result.mutates() {
-                let data = self.store.get(key, SequenceNumber::default());
+        Ok((result.seq, self.publish(&result)))
+    }
 
-                let message = MessageFromDatabase::Init {
-                    data,
-                    key: key.clone(),
-                };
+    /// Notify debug connections, the replica callback and subscribers of
+    /// the given result, which has already been applied to the store.
+    fn publish(&mut self, result: &ApplyResult) -> Option<MessageFromDatabase> {
+        let key = &result.key;
 
-                self.debug_connections.retain(|conn| {
-                    if let Some(conn) = conn.upgrade() {
-                        (conn.callback)(&message);
-                        true
-                    } else {
-                        false
-                    }
-                });
-            } else if let Some(seq_value) = &result.broadcast {
-                let message = MessageFromDatabase::Push {
+        if !self.debug_connections.is_empty() {
+            let full = result.mutates().then(|| MessageFromDatabase::Init {
+                data: self.store.get(key, SequenceNumber::default()),
+                key: key.clone(),
+            });
+            let delta = result
+                .broadcast
+                .as_ref()
+                .map(|seq_value| MessageFromDatabase::Push {
                     key: key.clone(),
                     value: seq_value.value.clone(),
                     seq: seq_value.seq,
+                });
+
+            self.debug_connections.retain(|(conn, options)| {
+                let Some(conn) = conn.upgrade() else {
+                    return false;
                 };
-                self.debug_connections.retain(|conn| {
-                    if let Some(conn) = conn.upgrade() {
-                        (conn.callback)(&message);
-                        true
-                    } else {
-                        false
+
+                if options.matches(key) {
+                    // Relayed values have no full state to send, and
+                    // compactions have no value.
+                    let message = match options.mode {
+                        DebugMode::Full => full.as_ref().or(delta.as_ref()),
+                        DebugMode::Delta => delta.as_ref().or(full.as_ref()),
+                    };
+
+                    if let Some(message) = message {
+                        (conn.callback)(message);
                     }
-                });
-            }
+                }
+
+                true
+            });
         }
 
         if result.mutates() {
             if let Some(replica_callback) = &self.replica_callback {
-                (replica_callback)(&result);
+                (replica_callback)(result);
             }
         }
 
-        if let Some(seq_value) = result.broadcast {
+        if let Some(seq_value) = &result.broadcast {
             let message = MessageFromDatabase::Push {
                 key: key.clone(),
                 value: seq_value.value.clone(),
@@ -99,6 +190,53 @@ impl DatabaseInner {
         None
     }
 
+    /// Send an `Init` message for every key to the connections which would
+    /// receive changes to it, e.g. after the store has been replaced.
+    fn reinitialize(&mut self) {
+        let DatabaseInner {
+            subscriptions,
+            debug_connections,
+            store,
+            ..
+        } = self;
+
+        let data = store.dump();
+
+        for (key, listeners) in subscriptions.iter_mut() {
+            let message = MessageFromDatabase::Init {
+                data: data.get(key).cloned().unwrap_or_default(),
+                key: key.clone(),
+            };
+
+            listeners.retain(|conn| {
+                if let Some(conn) = conn.upgrade() {
+                    (conn.callback)(&message);
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+
+        debug_connections.retain(|(conn, options)| {
+            if let Some(conn) = conn.upgrade() {
+                for (key, values) in data.iter().filter(|(key, _)| options.matches(key)) {
+                    (conn.callback)(&MessageFromDatabase::Init {
+                        data: values.clone(),
+                        key: key.clone(),
+                    });
+                }
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub(crate) fn take_session(&mut self, token: &str) -> Option<Vec<Key>> {
+        self.detached_sessions.remove(token)
+    }
+
     pub fn subscribe(&mut self, key: &Key, connection: Weak<Connection>) {
         let listeners = self.subscriptions.entry(key.clone()).or_default();
         listeners.push(connection);
@@ -112,6 +250,20 @@ impl DatabaseInner {
             key: key.clone(),
         })
     }
+
+    pub fn get_at(&self, key: &Key, seq: SequenceNumber) -> Result<MessageFromDatabase> {
+        match self.store.get_at(key, seq) {
+            Some(data) => Ok(MessageFromDatabase::History {
+                key: key.clone(),
+                seq,
+                data,
+            }),
+            None => Err(Error::LimitExceeded(format!(
+                "History is not available at sequence number {}.",
+                seq.0
+            ))),
+        }
+    }
 }
 
 #[derive(Default, Clone)]
@@ -133,6 +285,83 @@ impl Database {
         }
     }
 
+    /// Return the values retained for `key` with a sequence number greater
+    /// than `seq`, without subscribing to further changes.
+    pub fn get(&self, key: &Key, seq: SequenceNumber) -> Vec<SequenceValue> {
+        self.inner.lock().unwrap().store.get(key, seq)
+    }
+
+    /// Return a copy of the current state of the database.
+    pub fn snapshot(&self) -> Store {
+        self.with_store(Store::clone)
+    }
+
+    /// Call `f` with the store while the database is locked, so that no
+    /// change can be applied (or reach the replica callback) meanwhile.
+    pub fn with_store<R>(&self, f: impl FnOnce(&Store) -> R) -> R {
+        f(&self.inner.lock().unwrap().store)
+    }
+
+    /// Keep the subscriptions of a connection which has gone away, so that a
+    /// client reconnecting with its session token can resume them. Returns
+    /// the token, or `None` if the client never sent a `Hello` message. The
+    /// caller should expire the session with [Database::expire_session] once
+    /// its grace window has passed.
+    pub fn detach(&self, conn: &Connection) -> Option<String> {
+        let token = conn.session()?;
+        self.inner
+            .lock()
+            .unwrap()
+            .detached_sessions
+            .insert(token.clone(), conn.subscriptions());
+
+        Some(token)
+    }
+
+    /// Forget a detached session, if it has not been resumed.
+    pub fn expire_session(&self, token: &str) {
+        self.inner.lock().unwrap().detached_sessions.remove(token);
+    }
+
+    /// Apply a result from another database's replica callback, notifying
+    /// subscribers as if the change had been pushed here.
+    pub fn apply_replicated(&self, result: &ApplyResult) {
+        let mut db = self.inner.lock().unwrap();
+
+        let mut result = result.clone();
+        result.stream_size = db.store.apply_result(&result);
+        db.publish(&result);
+    }
+
+    /// Replace the entire contents of the database, sending subscribers the
+    /// new values of the keys they are subscribed to.
+    pub fn reset(&self, store: Store) {
+        let mut db = self.inner.lock().unwrap();
+        let history = db.store.history_config();
+
+        db.store = store;
+        if let Some(history) = history {
+            db.store.enable_history(history);
+        }
+
+        db.reinitialize();
+    }
+
+    /// Retain values removed from streams, so that `get_at` messages can
+    /// read streams as of an earlier sequence number.
+    pub fn enable_history(&self, config: HistoryConfig) {
+        self.inner.lock().unwrap().store.enable_history(config);
+    }
+
+    /// Reject (or allow again) pushes from connections.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.inner.lock().unwrap().read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.inner.lock().unwrap().read_only
+    }
+
     pub fn set_replica_callback<F>(&mut self, callback: F)
     where
         F: Fn(&ApplyResult) + 'static + Send + Sync,
@@ -147,7 +376,9 @@ impl Database {
         Arc::new(Connection::new(callback, self.inner.clone()))
     }
 
-    pub fn connect_debug<F>(&self, callback: F) -> Arc<Connection>
+    /// Connect to the database, receiving the state of every key matching
+    /// `options` and then every change to those keys, without subscribing.
+    pub fn connect_debug<F>(&self, options: DebugOptions, callback: F) -> Arc<Connection>
     where
         F: Fn(&MessageFromDatabase) + 'static + Send + Sync,
     {
@@ -156,22 +387,39 @@ impl Database {
         let mut db = self.inner.lock().unwrap();
 
         for (key, values) in db.store.dump() {
-            let message = MessageFromDatabase::Init { data: values, key };
-            (conn.callback)(&message);
+            if options.matches(&key) {
+                let message = MessageFromDatabase::Init { data: values, key };
+                (conn.callback)(&message);
+            }
         }
 
-        db.debug_connections.push(Arc::downgrade(&conn));
+        db.debug_connections.push((Arc::downgrade(&conn), options));
         conn
     }
+
+    /// Like [Database::connect], but messages are yielded by the returned
+    /// connection as a stream instead of passed to a callback.
+    #[cfg(feature = "async")]
+    pub fn connect_async(&self) -> AsyncConnection {
+        AsyncConnection::new(|callback| self.connect(callback))
+    }
+
+    /// Like [Database::connect_debug], but messages are yielded by the
+    /// returned connection as a stream instead of passed to a callback.
+    #[cfg(feature = "async")]
+    pub fn connect_debug_async(&self, options: DebugOptions) -> AsyncConnection {
+        AsyncConnection::new(|callback| self.connect_debug(options, callback))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
+        chunks::{split_push, CHUNKS_CAPABILITY, MAX_CHUNK_SIZE},
         tests::MessageStash,
-        types::{Action, SequenceNumber, SequenceValue},
-        MessageToDatabase,
+        types::{Action, SequenceNumber, SequenceValue, PROTOCOL_VERSION},
+        Encoding, ErrorCode, MessageToDatabase,
     };
     use serde_json::json;
 
@@ -183,6 +431,7 @@ mod tests {
         conn.send_message(&MessageToDatabase::Get {
             seq: Some(SequenceNumber::default()),
             key: key.into(),
+            request_id: None,
         })
         .unwrap();
     }
@@ -192,6 +441,7 @@ mod tests {
             key: key.into(),
             value: json_to_cbor(value),
             action,
+            request_id: None,
         })
         .unwrap();
     }
@@ -560,4 +810,529 @@ mod tests {
             stash2.next()
         );
     }
+
+    #[test]
+    fn test_apply_replicated() {
+        let follower = Database::new();
+        let mut leader = Database::new();
+        {
+            let follower = follower.clone();
+            leader.set_replica_callback(move |result| follower.apply_replicated(result));
+        }
+
+        let (stash, callback) = MessageStash::new();
+        let follower_conn = follower.connect(callback);
+        subscribe(&follower_conn, "foo");
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                data: vec![],
+                key: "foo".into()
+            }),
+            stash.next()
+        );
+
+        let leader_conn = leader.connect(|_| ());
+        push(&leader_conn, "foo", json!(1), Action::Append);
+        push(&leader_conn, "foo", json!(2), Action::Append);
+        push(
+            &leader_conn,
+            "foo",
+            json!(3),
+            Action::Compact {
+                seq: SequenceNumber(2),
+            },
+        );
+        push(&leader_conn, "bar", json!(4), Action::Replace);
+
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(1)),
+                seq: SequenceNumber(1),
+            }),
+            stash.next()
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(2)),
+                seq: SequenceNumber(2),
+            }),
+            stash.next()
+        );
+
+        assert_eq!(leader.snapshot().dump(), follower.snapshot().dump());
+        assert_eq!(SequenceNumber(3), follower.snapshot().sequence_number());
+    }
+
+    #[test]
+    fn test_debug_connection() {
+        let db = Database::new();
+        let conn = db.connect(|_| ());
+        push(&conn, "doc/a", json!(1), Action::Append);
+        push(&conn, "other", json!(2), Action::Append);
+
+        let (full, callback) = MessageStash::new();
+        let _full_conn = db.connect_debug(
+            DebugOptions {
+                prefix: "doc/".to_string(),
+                mode: DebugMode::Full,
+            },
+            callback,
+        );
+        let (delta, callback) = MessageStash::new();
+        let _delta_conn = db.connect_debug(
+            DebugOptions {
+                prefix: "doc/".to_string(),
+                mode: DebugMode::Delta,
+            },
+            callback,
+        );
+
+        // Only keys matching the prefix are sent initially.
+        for stash in [&full, &delta] {
+            assert_eq!(
+                Some(MessageFromDatabase::Init {
+                    data: vec![SequenceValue {
+                        value: json_to_cbor(json!(1)),
+                        seq: SequenceNumber(1),
+                    }],
+                    key: "doc/a".into(),
+                }),
+                stash.next()
+            );
+            assert_eq!(None, stash.next());
+        }
+
+        push(&conn, "other", json!(3), Action::Append);
+        push(&conn, "doc/a", json!(4), Action::Append);
+
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                data: vec![
+                    SequenceValue {
+                        value: json_to_cbor(json!(1)),
+                        seq: SequenceNumber(1),
+                    },
+                    SequenceValue {
+                        value: json_to_cbor(json!(4)),
+                        seq: SequenceNumber(4),
+                    }
+                ],
+                key: "doc/a".into(),
+            }),
+            full.next()
+        );
+        assert_eq!(None, full.next());
+
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "doc/a".into(),
+                value: json_to_cbor(json!(4)),
+                seq: SequenceNumber(4),
+            }),
+            delta.next()
+        );
+        assert_eq!(None, delta.next());
+    }
+
+    #[test]
+    fn test_history() {
+        let db = Database::new();
+        db.enable_history(HistoryConfig {
+            max_values: 10,
+            max_age: None,
+        });
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        push(&conn, "foo", json!(1), Action::Append);
+        push(&conn, "foo", json!(2), Action::Append);
+        push(
+            &conn,
+            "foo",
+            json!(3),
+            Action::Compact {
+                seq: SequenceNumber(2),
+            },
+        );
+        push(&conn, "foo", json!(4), Action::Replace);
+
+        let value = |value, seq| SequenceValue {
+            value: json_to_cbor(json!(value)),
+            seq: SequenceNumber(seq),
+        };
+        let get_at = |seq| {
+            conn.send_message(&MessageToDatabase::GetAt {
+                key: "foo".into(),
+                seq: SequenceNumber(seq),
+                request_id: None,
+            })
+        };
+
+        assert_eq!(
+            Ok(Some(MessageFromDatabase::History {
+                key: "foo".into(),
+                seq: SequenceNumber(1),
+                data: vec![value(1, 1)],
+            })),
+            get_at(1)
+        );
+        // The compaction takes the sequence number of the change before it,
+        // so the compacted value replaces the values before it from seq 2.
+        assert_eq!(
+            Ok(Some(MessageFromDatabase::History {
+                key: "foo".into(),
+                seq: SequenceNumber(2),
+                data: vec![value(3, 2)],
+            })),
+            get_at(2)
+        );
+        assert_eq!(
+            Ok(Some(MessageFromDatabase::History {
+                key: "foo".into(),
+                seq: SequenceNumber(3),
+                data: vec![value(4, 3)],
+            })),
+            get_at(3)
+        );
+        assert!(matches!(get_at(4), Err(Error::LimitExceeded(_))));
+
+        // Drain the messages sent to the connection so far.
+        while stash.next().is_some() {}
+
+        // Values dropped from the archive can no longer be reconstructed.
+        db.enable_history(HistoryConfig {
+            max_values: 1,
+            max_age: None,
+        });
+        push(&conn, "foo", json!(5), Action::Replace);
+        push(&conn, "foo", json!(6), Action::Replace);
+        assert!(matches!(get_at(3), Err(Error::LimitExceeded(_))));
+        assert_eq!(
+            Ok(Some(MessageFromDatabase::History {
+                key: "foo".into(),
+                seq: SequenceNumber(4),
+                data: vec![value(5, 4)],
+            })),
+            get_at(4)
+        );
+    }
+
+    #[test]
+    fn test_read_only() {
+        let db = Database::new();
+        db.set_read_only(true);
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        let result = conn.send_message(&MessageToDatabase::Push {
+            key: "foo".into(),
+            value: json_to_cbor(json!(1)),
+            action: Action::Append,
+            request_id: None,
+        });
+
+        // The error is returned to the host, rather than sent to the client.
+        assert_eq!(
+            Some(ErrorCode::Permission),
+            result.err().map(|err| err.code())
+        );
+        assert_eq!(None, stash.next());
+        assert!(db.get(&"foo".into(), SequenceNumber::default()).is_empty());
+
+        db.set_read_only(false);
+        push(&conn, "foo", json!(1), Action::Append);
+        assert_eq!(1, db.get(&"foo".into(), SequenceNumber::default()).len());
+    }
+
+    #[test]
+    fn test_reset() {
+        let source = Database::new();
+        push(&source.connect(|_| ()), "foo", json!(1), Action::Append);
+
+        let db = Database::new();
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        subscribe(&conn, "foo");
+        stash.next();
+
+        db.reset(source.snapshot());
+
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                data: vec![SequenceValue {
+                    value: json_to_cbor(json!(1)),
+                    seq: SequenceNumber(1),
+                }],
+                key: "foo".into()
+            }),
+            stash.next()
+        );
+    }
+
+    #[test]
+    fn test_hello() {
+        let db = Database::new();
+        db.enable_history(HistoryConfig {
+            max_values: 10,
+            max_age: None,
+        });
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        let other = db.connect(|_| ());
+        assert_ne!(conn.id(), other.id());
+
+        conn.send_message(&MessageToDatabase::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec!["foo".to_string()],
+            session: None,
+            seq: None,
+            request_id: None,
+        })
+        .unwrap();
+
+        assert_eq!(
+            Some(MessageFromDatabase::Welcome {
+                version: PROTOCOL_VERSION,
+                connection_id: conn.id(),
+                features: vec![
+                    "ack".to_string(),
+                    "resume".to_string(),
+                    "chunks".to_string(),
+                    "history".to_string()
+                ],
+                session: conn.session().unwrap(),
+                resumed: false,
+            }),
+            stash.next()
+        );
+        assert!(conn.has_capability("foo"));
+        assert!(!conn.has_capability("bar"));
+        assert!(!conn.is_rejected());
+
+        assert!(matches!(
+            other.send_message(&MessageToDatabase::Hello {
+                version: PROTOCOL_VERSION + 1,
+                capabilities: vec![],
+                session: None,
+                seq: None,
+                request_id: None,
+            }),
+            Err(Error::Validation(_))
+        ));
+        assert!(other.is_rejected());
+    }
+
+    #[test]
+    fn test_ack() {
+        let db = Database::new();
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        push(&conn, "foo", json!(1), Action::Append);
+
+        let ack = conn
+            .send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(2)),
+                action: Action::Append,
+                request_id: Some(7),
+            })
+            .unwrap();
+        let expected = MessageFromDatabase::Ack {
+            request_id: 7,
+            seq: Some(SequenceNumber(2)),
+            error: None,
+            code: None,
+        };
+        assert_eq!(Some(&expected), ack.as_ref());
+        // The stream size is reported before the acknowledgement.
+        assert!(matches!(
+            stash.next(),
+            Some(MessageFromDatabase::StreamSize { size: 2, .. })
+        ));
+        assert_eq!(Some(expected), stash.next());
+
+        // Errors are returned, for the host to report in the acknowledgement.
+        db.set_read_only(true);
+        let message = MessageToDatabase::Push {
+            key: "foo".into(),
+            value: json_to_cbor(json!(3)),
+            action: Action::Append,
+            request_id: Some(8),
+        };
+        let err = conn.send_message(&message).unwrap_err();
+        assert_eq!(
+            MessageFromDatabase::Ack {
+                request_id: 8,
+                seq: None,
+                error: Some("This database is read-only.".to_string()),
+                code: Some(ErrorCode::Permission),
+            },
+            err.into_reply(message.request_id())
+        );
+        assert_eq!(None, stash.next());
+    }
+
+    #[test]
+    fn test_ack_without_callback() {
+        // As when messages are sent over HTTP, only the return value is seen.
+        let db = Database::new();
+        let conn = db.connect(|_| {});
+        push(&conn, "foo", json!(1), Action::Append);
+
+        let reply = conn
+            .send_message(&MessageToDatabase::Get {
+                key: "foo".into(),
+                seq: Some(SequenceNumber(0)),
+                request_id: Some(7),
+            })
+            .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![SequenceValue {
+                    value: json_to_cbor(json!(1)),
+                    seq: SequenceNumber(1),
+                }],
+            }),
+            reply
+        );
+
+        // Pushes return their acknowledgement, even with a stream size reply.
+        let reply = conn
+            .send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(2)),
+                action: Action::Append,
+                request_id: Some(8),
+            })
+            .unwrap();
+        assert!(matches!(
+            reply,
+            Some(MessageFromDatabase::Ack {
+                request_id: 8,
+                seq: Some(SequenceNumber(2)),
+                ..
+            })
+        ));
+    }
+
+    fn hello(conn: &Arc<Connection>, session: Option<String>, seq: u64) -> (String, bool) {
+        let welcome = conn
+            .send_message(&MessageToDatabase::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: vec![],
+                session,
+                seq: Some(SequenceNumber(seq)),
+                request_id: None,
+            })
+            .unwrap();
+
+        match welcome {
+            Some(MessageFromDatabase::Welcome {
+                session, resumed, ..
+            }) => (session, resumed),
+            _ => panic!("Expected a welcome message, got {:?}", welcome),
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let db = Database::new();
+        let conn = db.connect(|_| ());
+        let (session, resumed) = hello(&conn, None, 0);
+        assert!(!resumed);
+        subscribe(&conn, "foo");
+        push(&conn, "foo", json!(1), Action::Append);
+
+        assert_eq!(Some(session.clone()), db.detach(&conn));
+        drop(conn);
+
+        let other = db.connect(|_| ());
+        push(&other, "foo", json!(2), Action::Append);
+
+        // The values pushed since the client's last sequence number are sent
+        // before the welcome message.
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        let (new_session, resumed) = hello(&conn, Some(session.clone()), 1);
+        assert!(resumed);
+        assert_ne!(session, new_session);
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![SequenceValue {
+                    value: json_to_cbor(json!(2)),
+                    seq: SequenceNumber(2),
+                }],
+            }),
+            stash.next()
+        );
+        assert!(matches!(
+            stash.next(),
+            Some(MessageFromDatabase::Welcome { resumed: true, .. })
+        ));
+
+        // The new connection is subscribed.
+        push(&other, "foo", json!(3), Action::Append);
+        assert!(matches!(
+            stash.next(),
+            Some(MessageFromDatabase::Push {
+                seq: SequenceNumber(3),
+                ..
+            })
+        ));
+
+        // A session can only be resumed once, and not after it expires.
+        let (_, resumed) = hello(&db.connect(|_| ()), Some(session), 0);
+        assert!(!resumed);
+        db.detach(&conn);
+        db.expire_session(&new_session);
+        let (_, resumed) = hello(&db.connect(|_| ()), Some(new_session), 0);
+        assert!(!resumed);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let db = Database::new();
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        conn.send_message(&MessageToDatabase::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![CHUNKS_CAPABILITY.to_string()],
+            session: None,
+            seq: None,
+            request_id: None,
+        })
+        .unwrap();
+        stash.next();
+
+        // A value pushed in chunks is applied once the last one arrives.
+        let value = ciborium::Value::Bytes(vec![1; MAX_CHUNK_SIZE * 2]);
+        let chunks =
+            split_push(&"foo".into(), &value, &Action::Replace, 1, MAX_CHUNK_SIZE).unwrap();
+        assert_eq!(3, chunks.len());
+        for chunk in &chunks {
+            conn.send_message(chunk).unwrap();
+        }
+        assert_eq!(None, stash.next());
+
+        // It is sent back in chunks, which join to the data of an `Init`
+        // message.
+        subscribe(&conn, "foo");
+        let mut bytes = Vec::new();
+        while let Some(MessageFromDatabase::InitChunk { data, .. }) = stash.next() {
+            bytes.extend(data);
+        }
+        let data: Vec<SequenceValue> = Encoding::Cbor.decode(&bytes).unwrap();
+        assert_eq!(
+            vec![SequenceValue {
+                value,
+                seq: SequenceNumber(1),
+            }],
+            data
+        );
+    }
 }
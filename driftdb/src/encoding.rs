@@ -0,0 +1,159 @@
+//! The encodings a client can use to exchange messages with the database.
+
+use crate::error::{Error, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+pub const MESSAGE_PACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// Older name for the MessagePack media type, still used by some clients.
+const LEGACY_MESSAGE_PACK_CONTENT_TYPE: &str = "application/x-msgpack";
+
+/// A wire encoding for messages, negotiated per connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// JSON, sent as WebSocket text frames.
+    #[default]
+    Json,
+
+    /// CBOR, sent as WebSocket binary frames.
+    Cbor,
+
+    /// MessagePack, sent as WebSocket binary frames.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Result<Encoding> {
+        match name {
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            "msgpack" => Ok(Encoding::MessagePack),
+            _ => Err(Error::Parse(
+                "Unknown encoding; expected json, cbor or msgpack.".to_string(),
+            )),
+        }
+    }
+
+    /// Find the encoding for a `Content-Type` header value, ignoring any
+    /// parameters such as `charset`.
+    pub fn from_content_type(content_type: &str) -> Option<Encoding> {
+        let media_type = content_type.split(';').next()?.trim();
+        match media_type {
+            JSON_CONTENT_TYPE => Some(Encoding::Json),
+            CBOR_CONTENT_TYPE => Some(Encoding::Cbor),
+            MESSAGE_PACK_CONTENT_TYPE | LEGACY_MESSAGE_PACK_CONTENT_TYPE => {
+                Some(Encoding::MessagePack)
+            }
+            _ => None,
+        }
+    }
+
+    /// Find the first supported encoding listed in an `Accept` header value.
+    pub fn from_accept(accept: &str) -> Option<Encoding> {
+        accept.split(',').find_map(Encoding::from_content_type)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => JSON_CONTENT_TYPE,
+            Encoding::Cbor => CBOR_CONTENT_TYPE,
+            Encoding::MessagePack => MESSAGE_PACK_CONTENT_TYPE,
+        }
+    }
+
+    /// Whether messages in this encoding are sent as binary rather than text
+    /// WebSocket frames.
+    pub fn is_binary(&self) -> bool {
+        !matches!(self, Encoding::Json)
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| Error::Parse(e.to_string())),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes)
+                    .map_err(|e| Error::Parse(e.to_string()))?;
+                Ok(bytes)
+            }
+            // Structs are encoded as maps rather than arrays, so that messages
+            // have the same shape as in the other encodings.
+            Encoding::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|e| Error::Parse(e.to_string()))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Json => {
+                serde_json::from_slice(bytes).map_err(|e| Error::Parse(e.to_string()))
+            }
+            Encoding::Cbor => {
+                ciborium::de::from_reader(bytes).map_err(|e| Error::Parse(e.to_string()))
+            }
+            Encoding::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| Error::Parse(e.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        types::{Action, SequenceNumber, SequenceValue},
+        Key, MessageFromDatabase, MessageToDatabase,
+    };
+    use ciborium::value::Value;
+
+    #[test]
+    fn test_round_trip() {
+        let inbound = MessageToDatabase::Push {
+            key: Key::new("foo".to_string()),
+            value: Value::Map(vec![(Value::Text("bar".into()), Value::Integer(4.into()))]),
+            action: Action::Append,
+            request_id: Some(3),
+        };
+        let outbound = MessageFromDatabase::Init {
+            key: Key::new("foo".to_string()),
+            data: vec![SequenceValue {
+                value: Value::Text("baz".into()),
+                seq: SequenceNumber(7),
+            }],
+        };
+
+        for encoding in [Encoding::Json, Encoding::Cbor, Encoding::MessagePack] {
+            let bytes = encoding.encode(&inbound).unwrap();
+            let decoded: MessageToDatabase = encoding.decode(&bytes).unwrap();
+            assert_eq!(inbound, decoded);
+
+            let bytes = encoding.encode(&outbound).unwrap();
+            let decoded: MessageFromDatabase = encoding.decode(&bytes).unwrap();
+            assert_eq!(outbound, decoded);
+        }
+    }
+
+    #[test]
+    fn test_content_types() {
+        assert_eq!(
+            Some(Encoding::Json),
+            Encoding::from_content_type("application/json; charset=utf-8")
+        );
+        assert_eq!(
+            Some(Encoding::MessagePack),
+            Encoding::from_content_type("application/x-msgpack")
+        );
+        assert_eq!(None, Encoding::from_content_type("text/plain"));
+        assert_eq!(
+            Some(Encoding::Cbor),
+            Encoding::from_accept("text/html, application/cbor, application/json")
+        );
+        assert_eq!(None, Encoding::from_accept("*/*"));
+    }
+}
@@ -0,0 +1,147 @@
+//! The error type of the core library.
+
+use crate::MessageFromDatabase;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Identifies the kind of an [Error], so that clients receiving
+/// [MessageFromDatabase::Error] can switch on it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    DatabaseGone,
+    Permission,
+    Validation,
+    LimitExceeded,
+    Parse,
+
+    /// A code this version doesn't know, or none, as sent by older peers.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// The HTTP status code for an error of this kind, for hosts which
+    /// handle messages sent over HTTP.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ErrorCode::DatabaseGone => 503,
+            ErrorCode::Permission => 403,
+            ErrorCode::Validation | ErrorCode::Parse => 400,
+            ErrorCode::LimitExceeded => 422,
+            ErrorCode::Unknown => 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The database behind a connection has been dropped.
+    DatabaseGone,
+
+    /// The operation is not allowed, e.g. pushing to a read-only database.
+    Permission(String),
+
+    /// The input was understood but is not acceptable, e.g. an invalid room
+    /// ID or an unsupported protocol version.
+    Validation(String),
+
+    /// The request goes beyond a configured limit, e.g. reading history
+    /// which is no longer retained.
+    LimitExceeded(String),
+
+    /// The input could not be parsed or decoded.
+    Parse(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::DatabaseGone => ErrorCode::DatabaseGone,
+            Error::Permission(_) => ErrorCode::Permission,
+            Error::Validation(_) => ErrorCode::Validation,
+            Error::LimitExceeded(_) => ErrorCode::LimitExceeded,
+            Error::Parse(_) => ErrorCode::Parse,
+        }
+    }
+
+    /// The message reporting this error to a client: an
+    /// [MessageFromDatabase::Ack] carrying the error if the message which
+    /// failed had a `request_id`, and an [MessageFromDatabase::Error]
+    /// otherwise.
+    pub fn into_reply(self, request_id: Option<u64>) -> MessageFromDatabase {
+        match request_id {
+            Some(request_id) => MessageFromDatabase::Ack {
+                request_id,
+                seq: None,
+                error: Some(self.to_string()),
+                code: Some(self.code()),
+            },
+            None => self.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DatabaseGone => f.write_str("Database is gone."),
+            Error::Permission(message)
+            | Error::Validation(message)
+            | Error::LimitExceeded(message)
+            | Error::Parse(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for MessageFromDatabase {
+    fn from(err: Error) -> Self {
+        MessageFromDatabase::Error {
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_error_message() {
+        let message: MessageFromDatabase = Error::Permission("Nope.".to_string()).into();
+        assert_eq!(
+            json!({"type": "error", "code": "permission", "message": "Nope."}),
+            serde_json::to_value(message).unwrap()
+        );
+
+        let message: MessageFromDatabase = Error::DatabaseGone.into();
+        assert_eq!(
+            json!({"type": "error", "code": "database_gone", "message": "Database is gone."}),
+            serde_json::to_value(message).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_error_from_older_peer() {
+        // Codes are optional, and ones added later are tolerated.
+        for message in [
+            json!({"type": "error", "message": "Nope."}),
+            json!({"type": "error", "code": "something_new", "message": "Nope."}),
+        ] {
+            assert_eq!(
+                MessageFromDatabase::Error {
+                    code: ErrorCode::Unknown,
+                    message: "Nope.".to_string(),
+                },
+                serde_json::from_value(message).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! A versioned, self-describing format for moving the contents of a room
+//! between deployments, e.g. from the worker to `driftdb-server`.
+
+use crate::{
+    error::{Error, Result},
+    types::{SequenceNumber, SequenceValue},
+    Key, Store,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Identifies a CBOR document as an exported room.
+pub const EXPORT_FORMAT: &str = "driftdb-room";
+
+/// The current version of the export format. Exports with a newer version
+/// are rejected.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Media type of exported rooms.
+pub const EXPORT_CONTENT_TYPE: &str = "application/cbor";
+
+#[derive(Serialize, Deserialize)]
+struct Export {
+    format: String,
+    version: u32,
+
+    /// The sequence number of the room when it was exported.
+    seq: SequenceNumber,
+
+    /// Every key of the room, with its values.
+    data: HashMap<Key, Vec<SequenceValue>>,
+}
+
+impl Store {
+    /// Encode the contents of the store in the export format.
+    pub fn export(&self) -> Vec<u8> {
+        let export = Export {
+            format: EXPORT_FORMAT.to_string(),
+            version: EXPORT_VERSION,
+            seq: self.sequence_number(),
+            data: self.dump(),
+        };
+
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&export, &mut bytes).expect("Exports should encode as CBOR.");
+        bytes
+    }
+
+    /// Decode a store from the output of [Store::export]. Fails with
+    /// [Error::Parse] if the bytes are not an export, or [Error::Validation]
+    /// if they were exported by a newer version of DriftDB or their sequence
+    /// numbers are inconsistent.
+    pub fn import(bytes: &[u8]) -> Result<Store> {
+        let export: Export = ciborium::de::from_reader(bytes)
+            .map_err(|err| Error::Parse(format!("Invalid export: {}", err)))?;
+
+        if export.format != EXPORT_FORMAT {
+            return Err(Error::Parse(format!(
+                "Unknown export format: {}",
+                export.format
+            )));
+        }
+
+        if export.version > EXPORT_VERSION {
+            return Err(Error::Validation(format!(
+                "Unsupported export version: {}",
+                export.version
+            )));
+        }
+
+        for (key, values) in &export.data {
+            if values.windows(2).any(|pair| pair[0].seq >= pair[1].seq) {
+                return Err(Error::Validation(format!(
+                    "Values of key {} are not in sequence order.",
+                    key
+                )));
+            }
+
+            if values.last().is_some_and(|value| value.seq > export.seq) {
+                return Err(Error::Validation(format!(
+                    "Key {} has values after the export's sequence number {}.",
+                    key, export.seq.0
+                )));
+            }
+        }
+
+        Ok(Store::from_dump(export.data, export.seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Action;
+    use ciborium::value::Value;
+
+    #[test]
+    fn test_round_trip() {
+        let mut store = Store::default();
+        store.apply(&"foo".into(), Value::Integer(1.into()), &Action::Append);
+        store.apply(&"foo".into(), Value::Text("a".into()), &Action::Append);
+        store.apply(&"bar".into(), Value::Bool(true), &Action::Replace);
+        store.apply(&"bar".into(), Value::Null, &Action::Relay);
+
+        let imported = Store::import(&store.export()).unwrap();
+        assert_eq!(store.dump(), imported.dump());
+        assert_eq!(SequenceNumber(4), imported.sequence_number());
+    }
+
+    #[test]
+    fn test_rejects_other_documents() {
+        let encode = |value: &Value| {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(value, &mut bytes).unwrap();
+            bytes
+        };
+        let export = |format: &str, version: u32| {
+            Value::Map(vec![
+                (Value::Text("format".into()), Value::Text(format.into())),
+                (
+                    Value::Text("version".into()),
+                    Value::Integer(version.into()),
+                ),
+                (Value::Text("seq".into()), Value::Integer(0.into())),
+                (Value::Text("data".into()), Value::Map(vec![])),
+            ])
+        };
+
+        assert!(Store::import(&encode(&export(EXPORT_FORMAT, EXPORT_VERSION))).is_ok());
+        assert_eq!(
+            Some(Error::Parse("Unknown export format: other".to_string())),
+            Store::import(&encode(&export("other", EXPORT_VERSION))).err()
+        );
+        assert_eq!(
+            Some(Error::Validation(
+                "Unsupported export version: 2".to_string()
+            )),
+            Store::import(&encode(&export(EXPORT_FORMAT, EXPORT_VERSION + 1))).err()
+        );
+        assert!(matches!(Store::import(b"not cbor"), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn test_rejects_inconsistent_sequence_numbers() {
+        let value = |seq| SequenceValue {
+            value: Value::Null,
+            seq: SequenceNumber(seq),
+        };
+        let import = |seq, values| {
+            let export = Export {
+                format: EXPORT_FORMAT.to_string(),
+                version: EXPORT_VERSION,
+                seq: SequenceNumber(seq),
+                data: HashMap::from([("foo".into(), values)]),
+            };
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&export, &mut bytes).unwrap();
+            Store::import(&bytes)
+        };
+
+        assert!(import(2, vec![value(1), value(2)]).is_ok());
+        assert!(matches!(
+            import(2, vec![value(2), value(1)]),
+            Err(Error::Validation(_))
+        ));
+        assert!(matches!(
+            import(2, vec![value(1), value(1)]),
+            Err(Error::Validation(_))
+        ));
+        assert!(matches!(
+            import(1, vec![value(1), value(2)]),
+            Err(Error::Validation(_))
+        ));
+    }
+}
@@ -1,13 +1,26 @@
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "async")]
+mod async_connection;
+pub mod chunks;
+pub mod compression;
 mod connection;
 mod db;
+pub mod encoding;
+pub mod error;
+pub mod export;
 mod store;
 
 #[cfg(test)]
 mod tests;
 pub mod types;
 
-pub use db::Database;
-pub use store::{ApplyResult, DeleteInstruction, PushInstruction, Store, ValueLog};
+#[cfg(feature = "async")]
+pub use async_connection::{AsyncConnection, MAX_QUEUED_MESSAGES};
+pub use compression::Compression;
+pub use connection::Connection;
+pub use db::{Database, DebugMode, DebugOptions};
+pub use encoding::Encoding;
+pub use error::{Error, ErrorCode};
+pub use store::{ApplyResult, DeleteInstruction, HistoryConfig, PushInstruction, Store, ValueLog};
 pub use types::{Key, MessageFromDatabase, MessageToDatabase};
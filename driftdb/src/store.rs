@@ -1,19 +1,117 @@
 use crate::types::{Action, Key, SequenceNumber, SequenceValue};
 use ciborium::value::Value;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct ValueLog {
     pub values: VecDeque<SequenceValue>,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Store {
     subjects: HashMap<Key, ValueLog>,
     sequence_number: SequenceNumber,
+
+    /// Values which have been deleted or compacted away, if history is enabled.
+    history: Option<History>,
+}
+
+/// Bounds on the superseded values retained by a store's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Maximum number of superseded values retained across all keys.
+    pub max_values: usize,
+
+    /// If set, superseded values are dropped once this many sequence numbers
+    /// have been assigned since they were removed.
+    pub max_age: Option<u64>,
+}
+
+/// A value which has been removed from a key's stream.
+#[derive(Clone)]
+struct ArchivedValue {
+    key: Key,
+    value: SequenceValue,
+
+    /// Sequence number of the change which added the value. This is the
+    /// value's own sequence number, except for values added by compaction.
+    added_at: SequenceNumber,
+
+    /// Sequence number of the change which removed the value.
+    removed_at: SequenceNumber,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Clone)]
+struct History {
+    config: HistoryConfig,
+
+    /// Archived values, in the order they were removed.
+    archive: VecDeque<ArchivedValue>,
+
+    /// For each key whose stream starts with a compacted value, the sequence
+    /// number of that value and of the change which added it.
+    compacted: HashMap<Key, (SequenceNumber, SequenceNumber)>,
+
+    /// The earliest sequence number at which streams can be reconstructed.
+    available_from: SequenceNumber,
+}
+
+impl History {
+    fn new(config: HistoryConfig, available_from: SequenceNumber) -> Self {
+        Self {
+            config,
+            archive: VecDeque::new(),
+            compacted: HashMap::new(),
+            available_from,
+        }
+    }
+
+    /// The sequence number of the change which added `value` to `key`.
+    fn added_at(&self, key: &Key, value: &SequenceValue) -> SequenceNumber {
+        match self.compacted.get(key) {
+            Some((seq, added_at)) if *seq == value.seq => *added_at,
+            _ => value.seq,
+        }
+    }
+
+    fn archive(&mut self, key: &Key, value: SequenceValue, removed_at: SequenceNumber) {
+        let added_at = self.added_at(key, &value);
+        if added_at != value.seq {
+            self.compacted.remove(key);
+        }
+
+        self.archive.push_back(ArchivedValue {
+            key: key.clone(),
+            value,
+            added_at,
+            removed_at,
+        });
+    }
+
+    /// Drop archived values beyond the configured bounds.
+    fn trim(&mut self, current: SequenceNumber) {
+        while let Some(oldest) = self.archive.front() {
+            let too_old = self
+                .config
+                .max_age
+                .map(|max_age| current.0.saturating_sub(oldest.removed_at.0) > max_age)
+                .unwrap_or(false);
+
+            if !too_old && self.archive.len() <= self.config.max_values {
+                break;
+            }
+
+            // Streams can no longer be reconstructed from before this value
+            // was removed.
+            self.available_from = self.available_from.max(oldest.removed_at);
+            self.archive.pop_front();
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DeleteInstruction {
     /// Delete all values for the given subject.
     Delete,
@@ -22,7 +120,8 @@ pub enum DeleteInstruction {
     DeleteUpTo(SequenceNumber),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PushInstruction {
     /// Push the given value to the end of the subject.
     Push(SequenceValue),
@@ -31,7 +130,7 @@ pub enum PushInstruction {
     PushStart(SequenceValue),
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApplyResult {
     pub key: Key,
 
@@ -46,6 +145,11 @@ pub struct ApplyResult {
 
     /// The number of retained records for the given subject after applying the action.
     pub stream_size: usize,
+
+    /// The sequence number assigned to the change. Every change which
+    /// mutates the store is assigned its own, except compactions, which
+    /// carry the sequence number of the change before them.
+    pub seq: SequenceNumber,
 }
 
 impl ApplyResult {
@@ -59,14 +163,79 @@ impl Store {
         Self {
             subjects,
             sequence_number,
+            history: None,
         }
     }
 
+    /// Start retaining values which are removed from streams, so that
+    /// streams can be read as of an earlier sequence number with
+    /// [Store::get_at]. History starts from the current sequence number.
+    pub fn enable_history(&mut self, config: HistoryConfig) {
+        self.history = Some(History::new(config, self.sequence_number));
+    }
+
+    /// The bounds on the store's history, if it is enabled.
+    pub fn history_config(&self) -> Option<HistoryConfig> {
+        self.history.as_ref().map(|history| history.config)
+    }
+
+    /// Reconstruct the stream of `key` as it was just after the change with
+    /// sequence number `seq`. Returns `None` if history is not enabled, or
+    /// does not reach back that far.
+    pub fn get_at(&self, key: &Key, seq: SequenceNumber) -> Option<Vec<SequenceValue>> {
+        let history = self.history.as_ref()?;
+        if seq < history.available_from || seq > self.sequence_number {
+            return None;
+        }
+
+        let archived = history
+            .archive
+            .iter()
+            .filter(|archived| {
+                &archived.key == key && archived.added_at <= seq && archived.removed_at > seq
+            })
+            .map(|archived| archived.value.clone());
+
+        let current = self
+            .subjects
+            .get(key)
+            .into_iter()
+            .flat_map(|log| log.values.iter())
+            .filter(|value| history.added_at(key, value) <= seq)
+            .cloned();
+
+        let mut values: Vec<SequenceValue> = archived.chain(current).collect();
+        values.sort_by_key(|value| value.seq);
+
+        Some(values)
+    }
+
+    /// Build a store from the output of [Store::dump].
+    pub fn from_dump(
+        data: HashMap<Key, Vec<SequenceValue>>,
+        sequence_number: SequenceNumber,
+    ) -> Self {
+        let subjects = data
+            .into_iter()
+            .map(|(key, values)| {
+                let values = values.into_iter().collect();
+                (key, ValueLog { values })
+            })
+            .collect();
+
+        Self::new(subjects, sequence_number)
+    }
+
     fn next_seq(&mut self) -> SequenceNumber {
         self.sequence_number.0 += 1;
         self.sequence_number
     }
 
+    /// The most recently assigned sequence number.
+    pub fn sequence_number(&self) -> SequenceNumber {
+        self.sequence_number
+    }
+
     pub fn dump(&self) -> HashMap<Key, Vec<SequenceValue>> {
         self.subjects
             .iter()
@@ -75,10 +244,9 @@ impl Store {
     }
 
     pub fn get(&self, key: &Key, min_sequence: SequenceNumber) -> Vec<SequenceValue> {
-        let Some(log) = self.subjects
-            .get(key) else {
-                return vec![];
-            };
+        let Some(log) = self.subjects.get(key) else {
+            return vec![];
+        };
 
         log.values
             .iter()
@@ -99,6 +267,7 @@ impl Store {
                     push_instruction: Some(PushInstruction::Push(value.clone())),
                     broadcast: Some(value),
                     stream_size: 0,
+                    seq,
                 }
             }
             Action::Replace => {
@@ -111,17 +280,21 @@ impl Store {
                     push_instruction: Some(PushInstruction::Push(value.clone())),
                     broadcast: Some(value),
                     stream_size: 0,
+                    seq,
                 }
             }
             Action::Compact { seq } => ApplyResult {
                 key: key.clone(),
                 delete_instruction: Some(DeleteInstruction::DeleteUpTo(*seq)),
+                // Compacting doesn't change what readers see, so it doesn't
+                // consume a sequence number; the change carries the current one.
                 push_instruction: Some(PushInstruction::PushStart(SequenceValue {
                     value,
                     seq: *seq,
                 })),
                 broadcast: None,
                 stream_size: 0,
+                seq: self.sequence_number,
             },
             Action::Relay => {
                 let seq = self.next_seq();
@@ -131,23 +304,69 @@ impl Store {
                     push_instruction: None,
                     broadcast: Some(SequenceValue { value, seq }),
                     stream_size: 0,
+                    seq,
                 }
             }
         };
 
-        match &result.delete_instruction {
+        result.stream_size = self.apply_instructions(
+            key,
+            result.delete_instruction.as_ref(),
+            result.push_instruction.as_ref(),
+            result.seq,
+        );
+
+        result
+    }
+
+    /// Apply a result produced by another store, such as one being
+    /// replicated. Returns the number of values retained for the key.
+    pub fn apply_result(&mut self, result: &ApplyResult) -> usize {
+        self.sequence_number = self.sequence_number.max(result.seq);
+
+        self.apply_instructions(
+            &result.key,
+            result.delete_instruction.as_ref(),
+            result.push_instruction.as_ref(),
+            result.seq,
+        )
+    }
+
+    fn apply_instructions(
+        &mut self,
+        key: &Key,
+        delete_instruction: Option<&DeleteInstruction>,
+        push_instruction: Option<&PushInstruction>,
+        seq: SequenceNumber,
+    ) -> usize {
+        let removed: Vec<SequenceValue> = match delete_instruction {
             Some(DeleteInstruction::Delete) => {
                 let value_log = self.subjects.entry(key.clone()).or_default();
-                value_log.values.clear();
+                value_log.values.drain(..).collect()
             }
-            Some(DeleteInstruction::DeleteUpTo(seq)) => {
+            Some(DeleteInstruction::DeleteUpTo(up_to)) => {
                 let value_log = self.subjects.entry(key.clone()).or_default();
-                value_log.values.retain(|v| v.seq > *seq);
+                let (removed, kept): (VecDeque<_>, VecDeque<_>) =
+                    value_log.values.drain(..).partition(|v| v.seq <= *up_to);
+                value_log.values = kept;
+                removed.into()
             }
-            None => {}
+            None => vec![],
+        };
+
+        if let Some(history) = &mut self.history {
+            for value in removed {
+                history.archive(key, value, seq);
+            }
+
+            if let Some(PushInstruction::PushStart(value)) = push_instruction {
+                history.compacted.insert(key.clone(), (value.seq, seq));
+            }
+
+            history.trim(self.sequence_number);
         }
 
-        match &result.push_instruction {
+        match push_instruction {
             Some(PushInstruction::Push(value)) => {
                 let value_log = self.subjects.entry(key.clone()).or_default();
                 value_log.values.push_back(value.clone());
@@ -159,8 +378,6 @@ impl Store {
             None => {}
         }
 
-        result.stream_size = self.subjects.get(key).map(|v| v.values.len()).unwrap_or(0);
-
-        result
+        self.subjects.get(key).map(|v| v.values.len()).unwrap_or(0)
     }
 }
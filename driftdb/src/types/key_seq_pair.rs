@@ -1,4 +1,4 @@
-use crate::Key;
+use crate::{error::Error, Key};
 use std::str::FromStr;
 
 use super::SequenceNumber;
@@ -24,6 +24,7 @@ impl KeyAndSeq {
     }
 }
 
+#[allow(clippy::to_string_trait_impl)]
 impl ToString for KeyAndSeq {
     fn to_string(&self) -> String {
         format!("{}|{}|{:020}", self.key.len(), self.key, self.seq)
@@ -31,15 +32,24 @@ impl ToString for KeyAndSeq {
 }
 
 impl FromStr for KeyAndSeq {
-    type Err = &'static str;
+    type Err = Error;
 
-    fn from_str(s: &str) -> Result<Self, &'static str> {
-        let (key_len, rest) = s.split_at(s.find('|').unwrap());
-        let key_len = key_len.parse::<usize>().unwrap();
-        let (key, rest) = rest.split_at(key_len + 1);
-        let key = Key::new(key[1..].to_string());
-        let seq = SequenceNumber(rest[1..].parse::<u64>().unwrap());
-        Ok(Self { key, seq })
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::Parse(format!("Invalid key and sequence number: {}", s));
+
+        let (key_len, rest) = s.split_once('|').ok_or_else(invalid)?;
+        let key_len = key_len.parse::<usize>().map_err(|_| invalid())?;
+        let key = rest.get(..key_len).ok_or_else(invalid)?;
+        let seq = rest
+            .get(key_len..)
+            .and_then(|rest| rest.strip_prefix('|'))
+            .ok_or_else(invalid)?;
+        let seq = SequenceNumber(seq.parse::<u64>().map_err(|_| invalid())?);
+
+        Ok(Self {
+            key: Key::new(key.to_string()),
+            seq,
+        })
     }
 }
 
@@ -59,6 +69,34 @@ mod tests {
         assert_eq!(k, k2);
     }
 
+    #[test]
+    fn test_key_and_seq_with_separator() {
+        let k = KeyAndSeq {
+            key: Key::new("a|b".to_string()),
+            seq: SequenceNumber(7),
+        };
+        assert_eq!(k, KeyAndSeq::from_str(&k.to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_key_and_seq() {
+        for s in [
+            "",
+            "foo",
+            "x|foo|1",
+            "3|fo",
+            "3|foo",
+            "3|foo|bar",
+            "9|foo|1",
+        ] {
+            assert!(
+                matches!(KeyAndSeq::from_str(s), Err(Error::Parse(_))),
+                "{}",
+                s
+            );
+        }
+    }
+
     #[test]
     fn test_prefix() {
         let result = KeyAndSeq::prefix_str(&Key::new("foo".to_string()));
@@ -1,9 +1,49 @@
+use crate::error::{Error, ErrorCode, Result};
 use ciborium::value::Value;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
+mod bytes;
 pub mod key_seq_pair;
 
+/// Version of the message protocol spoken by this library, announced to
+/// clients in [MessageFromDatabase::Welcome].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version a client may announce in
+/// [MessageToDatabase::Hello].
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Maximum length of a caller-chosen room ID.
+pub const MAX_ROOM_ID_LENGTH: usize = 64;
+
+/// Check that a caller-chosen room ID is acceptable. Room IDs must be
+/// between 1 and [MAX_ROOM_ID_LENGTH] characters and consist only of ASCII
+/// letters, digits, `-` and `_`, so that they can be used in URLs unescaped.
+pub fn validate_room_id(room_id: &str) -> Result<()> {
+    let invalid = |message: &str| Err(Error::Validation(message.to_string()));
+
+    if room_id.is_empty() {
+        return invalid("Room ID must not be empty.");
+    }
+
+    if room_id.len() > MAX_ROOM_ID_LENGTH {
+        return Err(Error::Validation(format!(
+            "Room ID must be at most {} characters.",
+            MAX_ROOM_ID_LENGTH
+        )));
+    }
+
+    if !room_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return invalid("Room ID may only contain ASCII letters, digits, '-' and '_'.");
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Default, Deserialize, Hash)]
 pub struct Key(String);
 
@@ -12,6 +52,10 @@ impl Key {
         Key(s)
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     #[allow(clippy::len_without_is_empty)] // empty key is meaningless.
     pub fn len(&self) -> usize {
         self.0.len()
@@ -65,6 +109,26 @@ pub enum Action {
     Compact { seq: SequenceNumber },
 }
 
+impl Action {
+    /// Build an action from its `type` name and, for `compact`, its sequence
+    /// number. This is used where actions are given as separate values (such
+    /// as in URL query parameters) rather than as a JSON object.
+    pub fn from_parts(name: &str, seq: Option<SequenceNumber>) -> Result<Action> {
+        match (name, seq) {
+            ("relay", _) => Ok(Action::Relay),
+            ("append", _) => Ok(Action::Append),
+            ("replace", _) => Ok(Action::Replace),
+            ("compact", Some(seq)) => Ok(Action::Compact { seq }),
+            ("compact", None) => Err(Error::Validation(
+                "The compact action requires a sequence number.".to_string(),
+            )),
+            _ => Err(Error::Parse(
+                "Unknown action; expected relay, append, replace or compact.".to_string(),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MessageToDatabase {
@@ -77,6 +141,8 @@ pub enum MessageToDatabase {
 
         /// Describes the action that this should have on the state.
         action: Action,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
     },
     Get {
         /// Key to get.
@@ -84,10 +150,78 @@ pub enum MessageToDatabase {
         /// Sequence number to start from.
         #[serde(default = "default_seq")]
         seq: Option<SequenceNumber>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+    },
+    /// Read a key's stream as it was at an earlier point. Requires the
+    /// database to retain history.
+    GetAt {
+        /// Key to get.
+        key: Key,
+        /// Sequence number at which to read the stream.
+        seq: SequenceNumber,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
     },
     Ping {
         nonce: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+    },
+    /// Announce the client's protocol version and capabilities. Clients
+    /// should send this as their first message; the database replies with
+    /// [MessageFromDatabase::Welcome], or an error if the version is not
+    /// supported.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        /// Session token from the `Welcome` message of an earlier connection,
+        /// to resume its subscriptions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        /// When resuming, the last sequence number the client received.
+        /// Values after it are sent for every resumed key.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<SequenceNumber>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
     },
+    /// One part of a push too large to send in a single message. The parts
+    /// are consecutive slices of the CBOR encoding of the value, sent in
+    /// order; the value is pushed once the last part arrives.
+    PushChunk {
+        /// Key to push to. Must be the same in every chunk.
+        key: Key,
+        /// Action of the push. Must be the same in every chunk.
+        action: Action,
+        /// Identifies the upload among others in progress on the connection.
+        upload_id: u64,
+        /// Position of this chunk in the upload, from zero.
+        index: u32,
+        /// Number of chunks in the upload.
+        total: u32,
+        /// Sent as a base64 string in JSON.
+        #[serde(with = "bytes")]
+        data: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+    },
+}
+
+impl MessageToDatabase {
+    /// The client's identifier for this message, if it wants it to be
+    /// acknowledged with [MessageFromDatabase::Ack].
+    pub fn request_id(&self) -> Option<u64> {
+        match self {
+            MessageToDatabase::Push { request_id, .. }
+            | MessageToDatabase::Get { request_id, .. }
+            | MessageToDatabase::GetAt { request_id, .. }
+            | MessageToDatabase::Ping { request_id, .. }
+            | MessageToDatabase::Hello { request_id, .. }
+            | MessageToDatabase::PushChunk { request_id, .. } => *request_id,
+        }
+    }
 }
 
 fn default_seq() -> Option<SequenceNumber> {
@@ -112,7 +246,27 @@ pub enum MessageFromDatabase {
         key: Key,
         data: Vec<SequenceValue>,
     },
+    /// One part of an `Init` message too large to send whole, sent to
+    /// clients with the `chunks` capability. The parts are consecutive slices
+    /// of the CBOR encoding of the `data` field of the `Init` message.
+    InitChunk {
+        key: Key,
+        /// Position of this chunk, from zero.
+        index: u32,
+        /// Number of chunks in the message.
+        total: u32,
+        /// Sent as a base64 string in JSON.
+        #[serde(with = "bytes")]
+        data: Vec<u8>,
+    },
+    History {
+        key: Key,
+        seq: SequenceNumber,
+        data: Vec<SequenceValue>,
+    },
     Error {
+        #[serde(default)]
+        code: ErrorCode,
         message: String,
     },
     StreamSize {
@@ -122,4 +276,59 @@ pub enum MessageFromDatabase {
     Pong {
         nonce: Option<u64>,
     },
+    Welcome {
+        /// Protocol version spoken by the database.
+        version: u32,
+        connection_id: u64,
+        /// Optional features available on this connection.
+        features: Vec<String>,
+        /// Token with which a client reconnecting after this connection is
+        /// lost can resume its subscriptions.
+        session: String,
+        /// Whether the subscriptions of an earlier session were resumed.
+        resumed: bool,
+    },
+    /// Sent after handling a message which carried a `request_id`.
+    Ack {
+        request_id: u64,
+        /// Sequence number assigned to the change made by a `push` message.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<SequenceNumber>,
+        /// Set if the message failed, in place of a separate `error` message.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        /// The code of the error, if the message failed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_from_parts() {
+        assert_eq!(Ok(Action::Append), Action::from_parts("append", None));
+        assert_eq!(
+            Ok(Action::Compact {
+                seq: SequenceNumber(3)
+            }),
+            Action::from_parts("compact", Some(SequenceNumber(3)))
+        );
+        assert!(Action::from_parts("compact", None).is_err());
+        assert!(Action::from_parts("delete", None).is_err());
+    }
+
+    #[test]
+    fn test_validate_room_id() {
+        assert!(validate_room_id("my-room_1").is_ok());
+        assert!(validate_room_id(&"a".repeat(MAX_ROOM_ID_LENGTH)).is_ok());
+
+        assert!(validate_room_id("").is_err());
+        assert!(validate_room_id(&"a".repeat(MAX_ROOM_ID_LENGTH + 1)).is_err());
+        assert!(validate_room_id("my room").is_err());
+        assert!(validate_room_id("room/connect").is_err());
+        assert!(validate_room_id("ルーム").is_err());
+    }
 }
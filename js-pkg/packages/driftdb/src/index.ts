@@ -8,7 +8,15 @@ export { PresenceListener } from './presence'
 export type { PresenceMessage, WrappedPresenceMessage } from './presence'
 export { Reducer } from './reducer'
 export { StateListener } from './state'
-export type { ConnectionStatus, Key, MessageFromDb, MessageToDb, SequenceValue } from './types'
+export { PROTOCOL_VERSION } from './types'
+export type {
+  ConnectionStatus,
+  ErrorCode,
+  Key,
+  MessageFromDb,
+  MessageToDb,
+  SequenceValue
+} from './types'
 export { SyncedWebRTCConnections } from './webrtc'
 export type { DataChannelMsg } from './webrtc'
 
@@ -151,6 +159,11 @@ export class DbConnection {
             this.activeLatencyTest = null
           }
           break
+        case 'history':
+        case 'welcome':
+        case 'ack':
+          // Only delivered to message listeners.
+          break
         case 'error':
           console.error('Error from server:', message)
           break
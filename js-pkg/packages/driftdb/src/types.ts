@@ -1,3 +1,6 @@
+/** Version of the message protocol spoken by this client. */
+export const PROTOCOL_VERSION = 1
+
 export type Key = string
 export type SequenceNumber = number
 
@@ -5,6 +8,13 @@ export type Action =
   | { type: 'append' | 'replace' | 'relay' }
   | { type: 'compact'; seq: SequenceNumber }
 
+export type ErrorCode =
+  | 'database_gone'
+  | 'permission'
+  | 'validation'
+  | 'limit_exceeded'
+  | 'parse'
+
 export interface SequenceValue {
   value: unknown
   seq: SequenceNumber
@@ -22,8 +32,22 @@ export type MessageFromDb =
       data: Array<SequenceValue>
       key: Key
     }
+  | {
+      type: 'init_chunk'
+      key: Key
+      index: number
+      total: number
+      data: string | Uint8Array
+    }
+  | {
+      type: 'history'
+      key: Key
+      seq: SequenceNumber
+      data: Array<SequenceValue>
+    }
   | {
       type: 'error'
+      code: ErrorCode
       message: string
     }
   | {
@@ -35,6 +59,21 @@ export type MessageFromDb =
       type: 'pong'
       nonce?: number
     }
+  | {
+      type: 'welcome'
+      version: number
+      connection_id: number
+      features: Array<string>
+      session: string
+      resumed: boolean
+    }
+  | {
+      type: 'ack'
+      request_id: number
+      seq?: SequenceNumber
+      error?: string
+      code?: ErrorCode
+    }
 
 export type MessageToDb =
   | {
@@ -42,15 +81,42 @@ export type MessageToDb =
       action: Action
       value: unknown
       key: Key
+      request_id?: number
+    }
+  | {
+      type: 'push_chunk'
+      key: Key
+      action: Action
+      upload_id: number
+      index: number
+      total: number
+      data: string | Uint8Array
+      request_id?: number
     }
   | {
       type: 'get'
       key: Key
       seq?: SequenceNumber | null
+      request_id?: number
+    }
+  | {
+      type: 'get_at'
+      key: Key
+      seq: SequenceNumber
+      request_id?: number
     }
   | {
       type: 'ping'
       nonce?: number
+      request_id?: number
+    }
+  | {
+      type: 'hello'
+      version: number
+      capabilities?: Array<string>
+      session?: string
+      seq?: SequenceNumber
+      request_id?: number
     }
 
 export type ConnectionStatus =